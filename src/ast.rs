@@ -1,6 +1,6 @@
-use std::iter::Peekable;
+use std::{ffi::{OsStr, OsString}, iter::Peekable};
 
-use crate::lexer::{Token, Lexer};
+use crate::{expand, lexer::{Part, Token, Lexer, Word}};
 
 type LexInput<'a> = Peekable<Lexer<'a>>;
 type LexOutput<'a, T> = Result<(T, Peekable<Lexer<'a>>), &'static str>;
@@ -9,32 +9,78 @@ pub trait FromLexer<'a>: Sized {
     fn from_lexer(lexer: LexInput<'a>) -> LexOutput<'a, Self>;
 }
 
-// Expr := MultiCmd
-// MultiCmd := Cmd ; MultiCmd | e
-// Cmd := Once | Redirect | 
-#[derive(Debug)]
-pub enum Expr<'a> {
-    NoOp,
-    Seq(Combinator<'a>, Box<Expr<'a>>),
+/// Takes the next token if it is an argument, failing on input the lexer
+/// could not split.
+fn next_arg<'a>(lexer: &mut LexInput<'a>) -> Result<Option<Word<'a>>, &'static str> {
+    match lexer.next_if(|t| !matches!(t, Token::Op(_))) {
+        Some(Token::Arg(arg)) => Ok(Some(arg)),
+        Some(Token::Invalid(reason)) => Err(reason),
+        _ => Ok(None),
+    }
 }
 
+// Expr := AndOr ; Expr | AndOr | e
+// AndOr := Combinator && AndOr | Combinator || AndOr | Combinator
+// Combinator := Stage '|' Combinator | Stage | Stage Output
+// Stage := Cmd Redirect*
+// Redirect := < Arg | 2> Arg | 2>> Arg
+// Output := > Arg | >> Arg | >+ Arg, among a last stage's redirects
+//
+/// A `;`-separated sequence, kept flat so arbitrarily long lines never
+/// recurse while parsing, running or dropping.
+#[derive(Debug)]
+pub struct Expr<'a>(pub Vec<AndOr<'a>>);
+
 impl<'a> FromLexer<'a> for Expr<'a> {
     fn from_lexer(mut lexer: LexInput<'a>) -> LexOutput<'a, Self> {
-        if lexer.peek().is_none() {
-            return Ok((Self::NoOp, lexer));
+        let mut seq = Vec::new();
+        while lexer.peek().is_some() {
+            let and_or;
+            (and_or, lexer) = AndOr::from_lexer(lexer)?;
+            seq.push(and_or);
+            if lexer.next_if_eq(&Token::Op(";")).is_none() {
+                break;
+            }
         }
-        let combinator;
-        (combinator, lexer) = Combinator::from_lexer(lexer)?;
-        let rest;
-        if lexer.next_if_eq(&Token::Op(";")).is_some() {
-            (rest, lexer) = Self::from_lexer(lexer)?
-        } else {
-            rest = Self::NoOp
-        };
-        Ok((Self::Seq(combinator, Box::new(rest)), lexer))
+        if lexer.peek().is_some() {
+            return Err(UNEXPECTED_TOKEN);
+        }
+        Ok((Self(seq), lexer))
     }
 }
 
+impl<'a> Expr<'a> {
+    /// Parses the longest leading run of `;`-terminated commands, so the
+    /// caller can still run those when a later command is malformed.
+    /// Returns the parsed prefix along with the error that stopped parsing.
+    pub fn parse_leading(lexer: Lexer<'a>) -> (Self, Option<&'static str>) {
+        let mut lexer = lexer.peekable();
+        let mut seq = Vec::new();
+        while lexer.peek().is_some() {
+            match AndOr::from_lexer(lexer) {
+                Ok((and_or, rest)) => {
+                    lexer = rest;
+                    if lexer.next_if_eq(&Token::Op(";")).is_some() {
+                        seq.push(and_or);
+                    } else if lexer.peek().is_none() {
+                        seq.push(and_or);
+                        break;
+                    } else {
+                        // a command followed by stray input is malformed itself
+                        return (Self(seq), Some(UNEXPECTED_TOKEN));
+                    }
+                }
+                Err(reason) => return (Self(seq), Some(reason)),
+            }
+        }
+        (Self(seq), None)
+    }
+}
+
+/// What a line fails with when a command is followed by input that can't
+/// continue it, like an argument after a redirect's target.
+const UNEXPECTED_TOKEN: &str = "unexpected token";
+
 impl<'a> TryFrom<Lexer<'a>> for Expr<'a> {
     type Error = &'static str;
 
@@ -43,86 +89,420 @@ impl<'a> TryFrom<Lexer<'a>> for Expr<'a> {
     }
 }
 
+/// How a pipeline in an `AndOr` list depends on the status before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Junction {
+    /// `&&`: run only if the previous status was zero.
+    And,
+    /// `||`: run only if the previous status was non-zero.
+    Or,
+}
+
+/// Pipelines joined by `&&` and `||`, which share one precedence and run
+/// left to right, each deciding from the status of whatever last ran.
 #[derive(Debug)]
-pub enum Combinator<'a> {
-    Identity(Cmd<'a>),
-    Redirect(Cmd<'a>, &'a str),
-    RedirectAppend(Cmd<'a>, &'a str),
-    RedirectInsert(Cmd<'a>, &'a str),
+pub struct AndOr<'a>(pub Combinator<'a>, pub Vec<(Junction, Combinator<'a>)>);
+
+impl<'a> FromLexer<'a> for AndOr<'a> {
+    fn from_lexer(mut lexer: LexInput<'a>) -> LexOutput<'a, Self> {
+        let first;
+        (first, lexer) = Combinator::from_lexer(lexer)?;
+        let mut rest = Vec::new();
+        while let Some(op) = lexer.next_if(|t| matches!(t, Token::Op("&&") | Token::Op("||"))) {
+            let junction = match op {
+                Token::Op("&&") => Junction::And,
+                Token::Op("||") => Junction::Or,
+                _ => panic!("unexpected operator"),
+            };
+            if lexer.peek().is_none() {
+                return Err("expected command after '&&' or '||'");
+            }
+            let combinator;
+            (combinator, lexer) = Combinator::from_lexer(lexer)?;
+            rest.push((junction, combinator));
+        }
+        Ok((Self(first, rest), lexer))
+    }
+}
+
+/// Where `>`, `>>` and `>+` put a pipeline's output in the file they name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redirect {
+    /// `>`: in place of what the file held.
+    Truncate,
+    /// `>>`: after it.
+    Append,
+    /// `>+`: before it.
+    Insert,
+}
+
+/// Commands joined by `|`, and where the last one's output is redirected
+/// to. Kept flat like `Expr`, so a pipeline of any length never recurses
+/// while parsing, running or dropping.
+#[derive(Debug)]
+pub struct Combinator<'a> {
+    pub stages: Vec<Stage<'a>>,
+    pub output: Option<(Redirect, Word<'a>)>,
+}
+
+/// A command in a pipeline, with the file its `<` reads from and the one
+/// its `2>` or `2>>` writes errors to, if it has them.
+#[derive(Debug)]
+pub struct Stage<'a> {
+    pub cmd: Simple<'a>,
+    pub input: Option<Word<'a>>,
+    pub errors: Option<(Redirect, Word<'a>)>,
 }
 
 impl<'a> FromLexer<'a> for Combinator<'a> {
     fn from_lexer(mut lexer: LexInput<'a>) -> LexOutput<'a, Self> {
-        let cmd;
-        (cmd, lexer) = Cmd::from_lexer(lexer)?;
-        Ok((if let Some(op) = lexer.next_if(|t| match t {
-            Token::Op(">") => true,
-            Token::Op(">>") => true,
-            Token::Op(">+") => true,
-            _ => false,
-        }) {
-            let out = lexer.next_if(Token::is_arg).ok_or("expected argument")?.unwrap();
-            match *op {
-                ">" => Self::Redirect(cmd, out),
-                ">>" => Self::RedirectAppend(cmd, out),
-                ">+" => Self::RedirectInsert(cmd, out),
-                _ => panic!("unexpected operator"),
+        let mut stages = Vec::new();
+        loop {
+            let cmd;
+            (cmd, lexer) = Simple::from_lexer(lexer)?;
+            let mut stage = Stage { cmd, input: None, errors: None };
+            let mut output = None;
+            // each kind of redirect is only kept once, so a second would be lost
+            while let Some(op) = lexer.next_if(|t| matches!(t, Token::Op("<" | ">" | ">>" | ">+" | "2>" | "2>>"))) {
+                let target = next_arg(&mut lexer)?.ok_or("expected argument")?;
+                let repeated = match op {
+                    Token::Op("<") => stage.input.replace(target).is_some(),
+                    Token::Op(">") => output.replace((Redirect::Truncate, target)).is_some(),
+                    Token::Op(">>") => output.replace((Redirect::Append, target)).is_some(),
+                    Token::Op(">+") => output.replace((Redirect::Insert, target)).is_some(),
+                    Token::Op("2>") => stage.errors.replace((Redirect::Truncate, target)).is_some(),
+                    Token::Op("2>>") => stage.errors.replace((Redirect::Append, target)).is_some(),
+                    _ => panic!("unexpected operator"),
+                };
+                if repeated && matches!(op, Token::Op("2>" | "2>>")) {
+                    return Err("only one error redirect is allowed");
+                } else if repeated {
+                    return Err("only one input and one output redirect are allowed");
+                }
+            }
+            stages.push(stage);
+            // a stage whose output is redirected has nothing left to pipe
+            if output.is_some() || lexer.next_if_eq(&Token::Op("|")).is_none() {
+                return Ok((Self { stages, output }, lexer));
+            }
+            if lexer.peek().is_none() {
+                return Err("expected command after '|'");
             }
-        } else {
-            Self::Identity(cmd)
-        }, lexer))
+        }
+    }
+}
+
+/// A command as written, before its words are expanded. Leading
+/// `NAME=value` words are kept apart as assignments: on their own they set
+/// variables for the session, and before a command only for that command.
+#[derive(Debug)]
+pub struct Simple<'a> {
+    pub assignments: Vec<(&'a str, Word<'a>)>,
+    pub words: Vec<Word<'a>>,
+}
+
+impl<'a> FromLexer<'a> for Simple<'a> {
+    fn from_lexer(mut lexer: LexInput<'a>) -> LexOutput<'a, Self> {
+        if matches!(lexer.peek(), Some(Token::Op(_))) {
+            return Err("expected argument or empty line");
+        }
+        let mut assignments = Vec::new();
+        let mut words = Vec::new();
+        while let Some(word) = next_arg(&mut lexer)? {
+            // once the command name is seen, an `=` is just part of an argument
+            match words.is_empty().then(|| assignment(&word)).flatten() {
+                Some(assignment) => assignments.push(assignment),
+                None => words.push(word),
+            }
+        }
+        Ok((Self { assignments, words }, lexer))
     }
 }
 
+/// Splits a `NAME=value` word into the name and the word for the value. The
+/// name and `=` have to be unquoted, so `'NAME=value'` is an ordinary word.
+fn assignment<'a>(word: &Word<'a>) -> Option<(&'a str, Word<'a>)> {
+    let (Part::Unquoted(first), rest) = word.0.split_first()? else {
+        return None;
+    };
+    let (name, value) = first.split_once('=')?;
+    if !expand::is_name(name) {
+        return None;
+    }
+    let value = Some(Part::Unquoted(value)).filter(|_| !value.is_empty());
+    Some((name, Word(value.into_iter().chain(rest.iter().copied()).collect())))
+}
+
+/// Whether `cd` follows the path as written (`-L`) or resolves symlinks (`-P`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CdMode {
+    Logical,
+    Physical,
+}
+
+/// A command once its words are expanded, told apart as a builtin or a
+/// program to run. Paths and program arguments are OS strings, since they
+/// needn't be valid UTF-8.
 #[derive(Debug)]
 pub enum Cmd<'a> {
-    Exit,
-    Cd(Option<&'a str>),
+    /// Leave the shell, with the status given or else the last one.
+    Exit(Option<i64>),
+    /// `exit` with an argument that isn't a number, which still leaves the
+    /// shell, with status 2, as other shells do.
+    ExitNonNumeric(&'a OsStr),
+    /// Define each alias given a body and print the others; list them all
+    /// if there are none.
+    Alias(Vec<(&'a str, Option<&'a str>)>),
+    Cd(CdMode, Option<&'a OsStr>),
+    Cdf(&'a OsStr),
     Pwd,
-    Other(&'a str, Vec<&'a str>),
+    Clear,
+    /// Print the arguments, and a newline unless `-n` was given.
+    Echo { newline: bool, args: Vec<&'a OsStr> },
+    /// Set each variable that has a value; list them all if there are none.
+    Export(Vec<(&'a str, Option<&'a OsStr>)>),
+    Finfo(Vec<&'a OsStr>),
+    Mktemp { dir: bool, template: Option<&'a OsStr> },
+    Nice(i32, &'a OsStr, Vec<&'a OsStr>),
+    Other(&'a OsStr, Vec<&'a OsStr>),
+    Unalias { all: bool, names: Vec<&'a str> },
+    Unset(Vec<&'a str>),
     NoOp,
 }
 
-impl<'a> FromLexer<'a> for Cmd<'a> {
-    fn from_lexer(mut lexer: LexInput<'a>) -> LexOutput<'a, Self> {
-        match lexer.next() {
-            Some(Token::Arg(cmd)) => {
-                let mut args = Vec::new();
-                while let Some(arg) = lexer.next_if(Token::is_arg) {
-                    args.push(*arg);
+/// Names `Cmd::try_from` recognizes as builtins rather than programs.
+pub const BUILTINS: &[&str] = &[
+    "exit", "alias", "cd", "cdf", "pwd", "clear", "echo", "export", "finfo", "mktemp", "nice", "unalias",
+    "unset",
+];
+
+impl<'a> TryFrom<&'a [OsString]> for Cmd<'a> {
+    type Error = &'static str;
+
+    fn try_from(words: &'a [OsString]) -> Result<Self, Self::Error> {
+        match words {
+            [] => Ok(Self::NoOp),
+            [cmd, args @ ..] => {
+                let args = args.iter().map(OsString::as_os_str).collect();
+                match cmd.to_str() {
+                    Some(cmd) => Self::try_from((cmd, args)),
+                    // builtin names are all UTF-8
+                    None => Ok(Self::Other(cmd, args)),
                 }
-                Self::try_from((cmd, args)).map(|cmd| (cmd, lexer))
             }
-            None => Ok((Self::NoOp, lexer)),
-            _ => Err("expected argument or empty line"),
         }
     }
 }
 
-impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Cmd<'a> {
+impl<'a> TryFrom<(&'a str, Vec<&'a OsStr>)> for Cmd<'a> {
     type Error = &'static str;
 
-    fn try_from((cmd, args): (&'a str, Vec<&'a str>)) -> Result<Self, Self::Error> {
+    fn try_from((cmd, args): (&'a str, Vec<&'a OsStr>)) -> Result<Self, Self::Error> {
         match cmd {
             "exit" =>
-                if args.len() == 0 {
-                    Ok(Self::Exit)
-                } else {
-                    Err("wrong number of arguments supplied to 'exit'")
+                match args.as_slice() {
+                    [] => Ok(Self::Exit(None)),
+                    [code] => match code.to_str().and_then(|code| code.parse().ok()) {
+                        Some(code) => Ok(Self::Exit(Some(code))),
+                        None => Ok(Self::ExitNonNumeric(code)),
+                    },
+                    _ => Err("wrong number of arguments supplied to 'exit'"),
+                }
+            "cd" => {
+                let mut mode = CdMode::Logical;
+                let mut operands = args.as_slice();
+                while let Some((opt, rest)) = operands.split_first() {
+                    // a lone `-` is an operand, not an option
+                    let Some(opt) = opt.to_str().filter(|opt| opt.starts_with('-') && *opt != "-") else {
+                        break;
+                    };
+                    operands = rest;
+                    if opt == "--" {
+                        break;
+                    }
+                    for flag in opt[1..].chars() {
+                        mode = match flag {
+                            'L' => CdMode::Logical,
+                            'P' => CdMode::Physical,
+                            _ => return Err("invalid option supplied to 'cd'"),
+                        };
+                    }
                 }
-            "cd" =>
-                match args.len() {
-                    0 => Ok(Self::Cd(None)),
-                    1 => Ok(Self::Cd(Some(args[0]))),
+                match operands {
+                    [] => Ok(Self::Cd(mode, None)),
+                    [path] => Ok(Self::Cd(mode, Some(path))),
                     _ => Err("wrong number of arguments supplied to 'cd'"),
                 }
+            }
+            "cdf" =>
+                match args.as_slice() {
+                    [path] => Ok(Self::Cdf(path)),
+                    _ => Err("wrong number of arguments supplied to 'cdf'"),
+                }
             "pwd" =>
-                if args.len() == 0 {
+                if args.is_empty() {
                     Ok(Self::Pwd)
                 } else {
                     Err("wrong number of arguments supplied to 'pwd'")
                 }
-            _ => Ok(Self::Other(cmd, args))
+            "clear" =>
+                if args.is_empty() {
+                    Ok(Self::Clear)
+                } else {
+                    Err("wrong number of arguments supplied to 'clear'")
+                }
+            "echo" => {
+                // any number of leading `-n`s, and everything after them is printed
+                let flags = args.iter().take_while(|arg| **arg == "-n").count();
+                Ok(Self::Echo { newline: flags == 0, args: args[flags..].to_vec() })
+            }
+            "export" =>
+                args.iter()
+                    .map(|&arg| match split_assignment(arg) {
+                        Some((name, value)) => Some((name, Some(value))),
+                        None => variable_name(arg).map(|name| (name, None)),
+                    })
+                    .collect::<Option<_>>()
+                    .map(Self::Export)
+                    .ok_or("invalid variable name supplied to 'export'"),
+            "alias" =>
+                args.iter()
+                    .map(|arg| {
+                        let arg = arg.to_str().ok_or("aliases must be valid UTF-8")?;
+                        let (name, body) = match arg.split_once('=') {
+                            Some((name, body)) => (name, Some(body)),
+                            None => (arg, None),
+                        };
+                        if is_alias_name(name) {
+                            Ok((name, body))
+                        } else {
+                            Err("invalid alias name supplied to 'alias'")
+                        }
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Self::Alias),
+            "unalias" => {
+                let (all, names) = match args.split_first() {
+                    Some((opt, rest)) if *opt == "-a" => (true, rest),
+                    _ => (false, args.as_slice()),
+                };
+                let names = names.iter()
+                    .map(|name| name.to_str().filter(|name| is_alias_name(name)))
+                    .collect::<Option<_>>()
+                    .ok_or("invalid alias name supplied to 'unalias'")?;
+                Ok(Self::Unalias { all, names })
+            }
+            "unset" =>
+                args.iter()
+                    .map(|&arg| variable_name(arg))
+                    .collect::<Option<_>>()
+                    .map(Self::Unset)
+                    .ok_or("invalid variable name supplied to 'unset'"),
+            "finfo" =>
+                if args.is_empty() {
+                    Err("wrong number of arguments supplied to 'finfo'")
+                } else {
+                    Ok(Self::Finfo(args))
+                }
+            "mktemp" => {
+                let (dir, operands) = match args.as_slice() {
+                    [flag, rest @ ..] if *flag == "-d" => (true, rest),
+                    rest => (false, rest),
+                };
+                match operands {
+                    [] => Ok(Self::Mktemp { dir, template: None }),
+                    [template] => Ok(Self::Mktemp { dir, template: Some(template) }),
+                    _ => Err("wrong number of arguments supplied to 'mktemp'"),
+                }
+            }
+            "nice" => {
+                let (adjustment, command) = match args.as_slice() {
+                    [flag, adjustment, command @ ..] if *flag == "-n" => (
+                        adjustment.to_str()
+                            .and_then(|adjustment| adjustment.parse().ok())
+                            .ok_or("invalid adjustment supplied to 'nice'")?,
+                        command,
+                    ),
+                    command => (10, command),
+                };
+                if !(-39..=39).contains(&adjustment) {
+                    return Err("adjustment supplied to 'nice' is out of range");
+                }
+                match command {
+                    [program, rest @ ..] => Ok(Self::Nice(adjustment, program, rest.to_vec())),
+                    [] => Err("wrong number of arguments supplied to 'nice'"),
+                }
+            }
+            _ => Ok(Self::Other(OsStr::new(cmd), args))
+        }
+    }
+}
+
+/// Whether `name` can be an alias: it mustn't need quoting, or it couldn't
+/// be typed as a command without losing the quotes that stop it expanding.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || "_-.:,+@%".contains(c))
+}
+
+/// The words of an alias body, which only stands in for the command word
+/// and so can't hold operators.
+pub fn alias_body<'a>(body: &'a str) -> Result<Vec<Word<'a>>, &'static str> {
+    Lexer::new(body)
+        .map(|token| match token {
+            Token::Arg(word) => Ok(word),
+            Token::Op(_) => Err("an alias can only hold words, not operators"),
+            Token::Invalid(reason) => Err(reason),
+        })
+        .collect()
+}
+
+fn variable_name(arg: &OsStr) -> Option<&str> {
+    arg.to_str().filter(|name| expand::is_name(name))
+}
+
+/// Splits a `NAME=value` argument at its first `=`, if what comes before it
+/// is a valid variable name.
+fn split_assignment(arg: &OsStr) -> Option<(&str, &OsStr)> {
+    let bytes = arg.as_encoded_bytes();
+    let equals = bytes.iter().position(|&b| b == b'=')?;
+    let name = std::str::from_utf8(&bytes[..equals]).ok().filter(|name| expand::is_name(name))?;
+    // SAFETY: the split comes right after an ASCII `=`, which leaves the
+    // rest valid encoded bytes
+    let value = unsafe { OsStr::from_encoded_bytes_unchecked(&bytes[equals + 1..]) };
+    Some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Expr<'_>, &'static str> {
+        Expr::try_from(Lexer::new(line))
+    }
+
+    #[test]
+    fn a_whole_line_parses() {
+        assert_eq!(parse("echo a > o; cat < o | wc && true || false").map(|expr| expr.0.len()), Ok(2));
+    }
+
+    #[test]
+    fn input_after_a_finished_command_is_an_error() {
+        assert_eq!(parse("echo hi > o1 extra; echo second").err(), Some(UNEXPECTED_TOKEN));
+    }
+
+    #[test]
+    fn a_second_redirect_of_either_kind_is_an_error() {
+        for line in ["echo a < f < g", "echo a > o2 > o3", "cat < f > o < g", "echo a >> o >+ p"] {
+            assert_eq!(parse(line).err(), Some("only one input and one output redirect are allowed"), "{}", line);
         }
+        assert_eq!(parse("cd x 2> a < f 2>> b").err(), Some("only one error redirect is allowed"));
+        assert!(parse("cd x 2> a < f > o").is_ok());
+    }
+
+    #[test]
+    fn parse_leading_keeps_only_commands_before_the_error() {
+        let (expr, error) = Expr::parse_leading(Lexer::new("echo ran; echo hi > o extra; echo no"));
+        assert_eq!(expr.0.len(), 1);
+        assert_eq!(error, Some(UNEXPECTED_TOKEN));
     }
 }
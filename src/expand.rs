@@ -0,0 +1,301 @@
+use std::{env, ffi::{OsStr, OsString}, io, mem};
+
+use crate::{glob, lexer::{Part, Word}, Shell};
+
+/// The arguments `word` expands to: usually just its text, but the sorted
+/// matching paths if it has unquoted wildcards that match anything.
+/// Otherwise the word stays as written, as in other shells. The output of
+/// an unquoted `$(...)` and the value of an unquoted variable are split
+/// into separate arguments at whitespace, so a word that is only an empty
+/// one of them vanishes.
+/// Arguments are OS strings, since file names and variables needn't be
+/// valid UTF-8.
+pub fn fields(word: &Word, shell: &Shell) -> io::Result<Vec<OsString>> {
+    let mut args = Vec::new();
+    for field in expand(word, shell, true)?.finish() {
+        if field.wildcards {
+            let paths = glob::matches(&field.pattern);
+            if !paths.is_empty() {
+                args.extend(paths);
+                continue;
+            }
+        }
+        args.push(field.text);
+    }
+    Ok(args)
+}
+
+/// The text of `word` once a leading `~`, the variables in its unquoted
+/// and double-quoted parts and its command substitutions are substituted.
+/// The result is always a single argument; it is not split again at
+/// whitespace, and nothing is globbed.
+pub fn word(word: &Word, shell: &Shell) -> io::Result<OsString> {
+    expand(word, shell, false).map(|expansion| expansion.current.text)
+}
+
+/// One argument as it is being expanded, kept both as plain text and as a
+/// glob pattern in which every char that was quoted is escaped.
+#[derive(Default)]
+struct Field {
+    text: OsString,
+    pattern: String,
+    wildcards: bool,
+}
+
+impl Field {
+    fn push(&mut self, text: &OsStr, quoted: bool) {
+        self.text.push(text);
+        for c in text.to_string_lossy().chars() {
+            if quoted && glob::is_special(c) || c == '\\' {
+                self.pattern.push('\\');
+            } else if glob::is_wildcard(c) {
+                self.wildcards = true;
+            }
+            self.pattern.push(c);
+        }
+    }
+}
+
+/// A word as it is being expanded: the fields that whitespace in a
+/// command's output already ended, and the one still being added to.
+struct Expansion {
+    fields: Vec<Field>,
+    current: Field,
+    /// Whether `current` is an argument even if it is empty, which it is
+    /// once anything quoted has been added to it.
+    keep: bool,
+    /// Whether substituted text outside quotes is split into fields.
+    split: bool,
+}
+
+impl Expansion {
+    fn push(&mut self, text: &OsStr, quoted: bool) {
+        self.keep |= quoted;
+        self.current.push(text, quoted);
+    }
+
+    /// Adds the value of a variable or the output of a `$(...)`, which is
+    /// split into fields if it is unquoted and the expansion splits.
+    fn push_value(&mut self, value: &OsStr, quoted: bool) {
+        if self.split && !quoted {
+            self.push_split(value);
+        } else {
+            self.push(value, quoted);
+        }
+    }
+
+    /// Adds substituted text, ending the current field at each run of
+    /// whitespace in it.
+    fn push_split(&mut self, output: &OsStr) {
+        for (i, piece) in output.as_encoded_bytes().split(u8::is_ascii_whitespace).enumerate() {
+            if i > 0 {
+                self.end_field();
+            }
+            // SAFETY: the pieces are split at ASCII whitespace, which leaves
+            // each one valid encoded bytes
+            self.current.push(unsafe { OsStr::from_encoded_bytes_unchecked(piece) }, false);
+        }
+    }
+
+    fn end_field(&mut self) {
+        let field = mem::take(&mut self.current);
+        if self.keep || !field.text.is_empty() {
+            self.fields.push(field);
+        }
+        self.keep = false;
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        self.end_field();
+        self.fields
+    }
+}
+
+/// Expands `word`, splitting unquoted variables and command substitutions
+/// into fields if `split` is set.
+fn expand(word: &Word, shell: &Shell, split: bool) -> io::Result<Expansion> {
+    let mut expansion = Expansion {
+        fields: Vec::new(),
+        current: Field::default(),
+        // an empty quoted word like '' still has no parts
+        keep: word.0.is_empty(),
+        split,
+    };
+    for (i, part) in word.0.iter().enumerate() {
+        match *part {
+            Part::Unquoted(part) if i == 0 => {
+                let rest = tilde(part, word.0.len() == 1, &mut expansion);
+                variables(rest, false, shell, &mut expansion)?
+            }
+            Part::Unquoted(part) => variables(part, false, shell, &mut expansion)?,
+            Part::DoubleQuoted(part) => variables(part, true, shell, &mut expansion)?,
+            Part::Literal(part) => expansion.push(part.as_ref(), true),
+            Part::Command(source) => expansion.push_value(&shell.substitute(source)?, false),
+            Part::QuotedCommand(source) => expansion.push_value(&shell.substitute(source)?, true),
+        }
+    }
+    Ok(expansion)
+}
+
+/// Appends `$HOME` to `out` if the word starts with an unquoted `~` that is
+/// either the whole word or followed by `/`, returning what is left of
+/// `first`, the word's first part. Otherwise the `~` is an ordinary char.
+fn tilde<'a>(first: &'a str, whole_word: bool, out: &mut Expansion) -> &'a str {
+    let Some(rest) = first.strip_prefix('~') else {
+        return first;
+    };
+    if !(rest.starts_with('/') || rest.is_empty() && whole_word) {
+        return first;
+    }
+    match env::var_os("HOME") {
+        Some(home) => {
+            out.push(&home, true);
+            rest
+        }
+        None => first,
+    }
+}
+
+/// Appends `text` to `out` with each `$NAME` and `${NAME}` replaced by that
+/// environment variable, or by nothing when it is unset, and each `$?` and
+/// `${?}` by the last command's status. A `$` that isn't followed by a name
+/// is kept as is. Values substituted outside quotes are split into fields
+/// and may still be globbed.
+fn variables(mut text: &str, quoted: bool, shell: &Shell, out: &mut Expansion) -> io::Result<()> {
+    while let Some(dollar) = text.find('$') {
+        out.push(text[..dollar].as_ref(), quoted);
+        let rest = &text[dollar + 1..];
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| bad_substitution("missing '}' after '${'"))?;
+            let name = &braced[..end];
+            if !(is_name(name) || name == "?") {
+                return Err(bad_substitution("bad substitution"));
+            }
+            (name, &braced[end + 1..])
+        } else if let Some(after) = rest.strip_prefix('?') {
+            ("?", after)
+        } else {
+            rest.split_at(name_len(rest))
+        };
+        if name.is_empty() {
+            out.push("$".as_ref(), quoted);
+        } else if name == "?" {
+            out.push(shell.status.to_string().as_ref(), quoted);
+        } else if let Some(value) = env::var_os(name) {
+            out.push_value(&value, quoted);
+        }
+        text = after;
+    }
+    out.push(text.as_ref(), quoted);
+    Ok(())
+}
+
+/// Whether `text` is a valid variable name.
+pub fn is_name(text: &str) -> bool {
+    !text.is_empty() && name_len(text) == text.len()
+}
+
+/// The length of the variable name `text` starts with: a letter or `_`
+/// followed by any number of letters, digits and `_`s.
+fn name_len(text: &str) -> usize {
+    if !text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return 0;
+    }
+    text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len())
+}
+
+fn bad_substitution(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::{Lexer, Token}, tests::lock, Flags};
+
+    fn parse(line: &str) -> Word<'_> {
+        let Some(Token::Arg(word)) = Lexer::new(line).next() else {
+            panic!("{} is not a word", line);
+        };
+        word
+    }
+
+    /// The arguments `line`, a single word, expands to in `shell`.
+    fn expand_in(shell: &Shell, line: &str) -> Vec<String> {
+        fields(&parse(line), shell).unwrap().into_iter().map(|arg| arg.into_string().unwrap()).collect()
+    }
+
+    fn expand_line(line: &str) -> Vec<String> {
+        expand_in(&Shell::new(Flags::default()), line)
+    }
+
+    #[test]
+    fn a_glob_matching_nothing_stays_as_written() {
+        assert_eq!(expand_line("/nonexistent-chitin-dir/*.none"), ["/nonexistent-chitin-dir/*.none"]);
+        assert_eq!(expand_line("'/nonexistent-chitin-dir/*'"), ["/nonexistent-chitin-dir/*"]);
+    }
+
+    #[test]
+    fn variables_expand_and_split_outside_quotes() {
+        let _lock = lock();
+        env::set_var("CHITIN_TEST_SET", "a  b");
+        env::remove_var("CHITIN_TEST_UNSET");
+        assert_eq!(expand_line("$CHITIN_TEST_SET"), ["a", "b"]);
+        assert_eq!(expand_line("\"$CHITIN_TEST_SET\""), ["a  b"]);
+        assert_eq!(expand_line("${CHITIN_TEST_SET}x"), ["a", "bx"]);
+        assert_eq!(expand_line("$CHITIN_TEST_SET.txt"), ["a", "b.txt"]);
+        assert_eq!(expand_line("'$CHITIN_TEST_SET'"), ["$CHITIN_TEST_SET"]);
+        assert_eq!(expand_line("x$CHITIN_TEST_UNSET"), ["x"]);
+        assert_eq!(expand_line("$CHITIN_TEST_UNSET"), Vec::<String>::new());
+        assert_eq!(expand_line("\"${CHITIN_TEST_UNSET}\""), [""]);
+        assert_eq!(expand_line("a$"), ["a$"]);
+        assert_eq!(expand_line("$-x"), ["$-x"]);
+        assert_eq!(word(&parse("$CHITIN_TEST_SET"), &Shell::new(Flags::default())).unwrap(), "a  b");
+        env::remove_var("CHITIN_TEST_SET");
+    }
+
+    #[test]
+    fn a_question_mark_expands_to_the_last_status() {
+        let mut shell = Shell::new(Flags::default());
+        shell.status = 3;
+        assert_eq!(expand_in(&shell, "$?"), ["3"]);
+        assert_eq!(expand_in(&shell, "x${?}y"), ["x3y"]);
+        assert_eq!(expand_in(&shell, "'$?'"), ["$?"]);
+    }
+
+    #[test]
+    fn a_malformed_brace_is_a_bad_substitution() {
+        let shell = Shell::new(Flags::default());
+        let error = |line| fields(&parse(line), &shell).unwrap_err().to_string();
+        assert_eq!(error("${HOME"), "missing '}' after '${'");
+        assert_eq!(error("${1x}"), "bad substitution");
+        assert_eq!(error("\"${}\""), "bad substitution");
+    }
+
+    #[test]
+    fn a_leading_tilde_expands_to_home() {
+        let _lock = lock();
+        let home = env::var_os("HOME");
+        env::set_var("HOME", "/home/chitin");
+        assert_eq!(expand_line("~"), ["/home/chitin"]);
+        assert_eq!(expand_line("~/a"), ["/home/chitin/a"]);
+        assert_eq!(expand_line("~a"), ["~a"]);
+        assert_eq!(expand_line("a~"), ["a~"]);
+        assert_eq!(expand_line("'~'/a"), ["~/a"]);
+        assert_eq!(expand_line("~'x'"), ["~x"]);
+        match home {
+            Some(home) => env::set_var("HOME", home),
+            None => env::remove_var("HOME"),
+        }
+    }
+
+    #[test]
+    fn unquoted_command_output_is_split() {
+        let _lock = lock();
+        assert_eq!(expand_line("$(echo 'a  b')"), ["a", "b"]);
+        assert_eq!(expand_line("\"$(echo 'a  b')\""), ["a  b"]);
+        assert_eq!(expand_line("x$(echo ' a ')y"), ["x", "a", "y"]);
+        assert_eq!(expand_line("$(true)"), Vec::<String>::new());
+        assert_eq!(expand_line("\"$(true)\""), [""]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{ffi::{OsStr, OsString}, fs};
+
+/// Whether `c` has to be escaped with a backslash to match itself.
+pub fn is_special(c: char) -> bool {
+    matches!(c, '*' | '?' | '[' | ']' | '\\')
+}
+
+/// Whether `c` makes the text around it a pattern when unescaped.
+pub fn is_wildcard(c: char) -> bool {
+    matches!(c, '*' | '?' | '[')
+}
+
+/// The existing paths matching `pattern`, sorted, or an empty list if there
+/// are none. Each `/`-separated component is matched against the entries
+/// of the directories matched by the components before it. As in other
+/// shells, names starting with `.` only match a component that starts with
+/// a literal `.`. Names that aren't valid UTF-8 are matched by their lossy
+/// conversion but returned as they are.
+pub fn matches(pattern: &str) -> Vec<OsString> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec![OsString::from("/")], rest),
+        None => (vec![OsString::new()], pattern),
+    };
+    let mut components = rest.split('/').peekable();
+    while let Some(component) = components.next() {
+        let last = components.peek().is_none();
+        let mut next = Vec::new();
+        for dir in paths {
+            if !has_wildcards(component) {
+                let mut path = dir;
+                path.push(unescape(component));
+                next.push(path);
+                continue;
+            }
+            let pieces = compile(component);
+            let Ok(entries) = fs::read_dir(if dir.is_empty() { OsStr::new(".") } else { &dir }) else {
+                continue;
+            };
+            let mut names: Vec<OsString> = entries
+                .flatten()
+                .map(|entry| entry.file_name())
+                .filter(|name| matches_name(&pieces, &name.to_string_lossy()))
+                .collect();
+            names.sort();
+            next.extend(names.into_iter().map(|name| {
+                let mut path = dir.clone();
+                path.push(name);
+                path
+            }));
+        }
+        paths = next;
+        if !last {
+            for path in &mut paths {
+                path.push("/");
+            }
+        }
+    }
+    // literal components after the last wildcard may name nothing
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths
+}
+
+fn has_wildcards(component: &str) -> bool {
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if is_wildcard(c) {
+            return true;
+        }
+    }
+    false
+}
+
+fn unescape(component: &str) -> String {
+    let mut text = String::new();
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        text.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+    }
+    text
+}
+
+enum Piece {
+    Char(char),
+    /// `?`
+    AnyChar,
+    /// `*`
+    AnyRun,
+    /// `[...]`, or `[!...]` when negated
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl Piece {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char(expected) => *expected == c,
+            Self::AnyChar => true,
+            Self::AnyRun => false,
+            Self::Class { negated, ranges } => ranges.iter().any(|&(low, high)| (low..=high).contains(&c)) != *negated,
+        }
+    }
+}
+
+fn compile(component: &str) -> Vec<Piece> {
+    let chars: Vec<char> = component.chars().collect();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                pieces.push(Piece::Char(chars[i]));
+            }
+            '*' => pieces.push(Piece::AnyRun),
+            '?' => pieces.push(Piece::AnyChar),
+            // an unterminated class is just a `[`
+            '[' => match class(&chars[i + 1..]) {
+                Some((piece, len)) => {
+                    pieces.push(piece);
+                    i += len;
+                }
+                None => pieces.push(Piece::Char('[')),
+            },
+            c => pieces.push(Piece::Char(c)),
+        }
+        i += 1;
+    }
+    pieces
+}
+
+/// Parses the class following a `[`, returning it along with the number of
+/// chars it spans, closing `]` included.
+fn class(chars: &[char]) -> Option<(Piece, usize)> {
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let start = i;
+    let mut ranges = Vec::new();
+    loop {
+        let mut low = *chars.get(i)?;
+        // a `]` right after the opening is a member, not the end
+        if low == ']' && i > start {
+            return Some((Piece::Class { negated, ranges }, i + 1));
+        }
+        if low == '\\' {
+            i += 1;
+            low = *chars.get(i)?;
+        }
+        let mut high = low;
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&end| end != ']') {
+            i += 2;
+            high = chars[i];
+            if high == '\\' {
+                i += 1;
+                high = *chars.get(i)?;
+            }
+        }
+        ranges.push((low, high));
+        i += 1;
+    }
+}
+
+fn matches_name(pieces: &[Piece], name: &str) -> bool {
+    if name.starts_with('.') && !matches!(pieces.first(), Some(Piece::Char('.'))) {
+        return false;
+    }
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // the last `*` seen and where in the name it would next resume from
+    let mut resume = None;
+    while n < name.len() {
+        match pieces.get(p) {
+            Some(Piece::AnyRun) => {
+                resume = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(piece) if piece.matches(name[n]) => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => (),
+        }
+        // let the last `*` swallow one more char and try again
+        let Some((star, from)) = resume else {
+            return false;
+        };
+        resume = Some((star, from + 1));
+        p = star + 1;
+        n = from + 1;
+    }
+    pieces[p..].iter().all(|piece| matches!(piece, Piece::AnyRun))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::{Path, PathBuf}};
+
+    use super::*;
+    use crate::temp;
+
+    /// A fresh directory holding empty files with the given names.
+    fn dir_with(names: &[&str]) -> PathBuf {
+        let dir = temp::dir(&env::temp_dir().join("chitin-glob.XXXXXX")).unwrap();
+        for name in names {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        dir
+    }
+
+    /// The names `pattern` matches inside `dir`.
+    fn names(dir: &Path, pattern: &str) -> Vec<String> {
+        let prefix = format!("{}/", dir.display());
+        matches(&format!("{}{}", prefix, pattern))
+            .into_iter()
+            .map(|path| path.into_string().unwrap().strip_prefix(&prefix).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn matches_are_sorted() {
+        let dir = dir_with(&["b.rs", "c.txt", "a.rs", "ab.rs"]);
+        assert_eq!(names(&dir, "*.rs"), ["a.rs", "ab.rs", "b.rs"]);
+        assert_eq!(names(&dir, "?.rs"), ["a.rs", "b.rs"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn classes_match_one_char() {
+        let dir = dir_with(&["a1", "b1", "c1", "d1", "-1"]);
+        assert_eq!(names(&dir, "[a-c]1"), ["a1", "b1", "c1"]);
+        assert_eq!(names(&dir, "[!a-c]1"), ["-1", "d1"]);
+        assert_eq!(names(&dir, "[bd-]1"), ["-1", "b1", "d1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_files_need_a_literal_dot() {
+        let dir = dir_with(&[".hidden", "shown"]);
+        assert_eq!(names(&dir, "*"), ["shown"]);
+        assert_eq!(names(&dir, "?hidden"), Vec::<String>::new());
+        assert_eq!(names(&dir, ".*"), [".hidden"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn components_are_matched_in_turn() {
+        let dir = dir_with(&[]);
+        fs::create_dir(dir.join("sub1")).unwrap();
+        fs::create_dir(dir.join("sub2")).unwrap();
+        fs::write(dir.join("sub2/f"), "").unwrap();
+        assert_eq!(names(&dir, "sub*/f"), ["sub2/f"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn no_match_is_empty_and_escapes_match_literally() {
+        let dir = dir_with(&["*star", "plain"]);
+        assert!(names(&dir, "*.none").is_empty());
+        assert_eq!(names(&dir, "\\**"), ["*star"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
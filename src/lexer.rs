@@ -1,138 +1,464 @@
-use std::ops::{Deref, DerefMut};
-
-
-const RESERVED_OP: &[&'static str] = &[
+const RESERVED_OP: &[&str] = &[
     ";",
+    // before ">" so neither is read as ">" and an argument
     ">>",
-    ">",
     ">+",
+    ">",
+    "<",
+    // before "|" so "||" isn't read as two pipes
+    "&&",
+    "||",
     "|",
 ];
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Token<'a> {
-    Arg(&'a str),
+    Arg(Word<'a>),
     Op(&'a str),
+    /// Input that cannot be split into tokens, like an unterminated quote.
+    Invalid(&'static str),
+}
+
+/// A word with its quotes removed, split wherever the quoting changes so
+/// expansion can tell which parts it may touch.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Word<'a>(pub Vec<Part<'a>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part<'a> {
+    /// Text outside quotes.
+    Unquoted(&'a str),
+    /// Text inside double quotes, where variables still expand.
+    DoubleQuoted(&'a str),
+    /// Text taken exactly as written: single-quoted or backslash-escaped.
+    Literal(&'a str),
+    /// The commands in a `$(...)` outside quotes, whose output is split
+    /// into words.
+    Command(&'a str),
+    /// The commands in a `$(...)` inside double quotes, whose output stays
+    /// part of a single word.
+    QuotedCommand(&'a str),
 }
 
-impl<'a> Token<'a> {
-    pub fn is_arg(&self) -> bool {
-        match self {
-            Self::Arg(_) => true,
-            _ => false,
+impl<'a> Word<'a> {
+    /// Adds a part, leaving out empty text since it expands to nothing.
+    fn push(&mut self, part: Part<'a>) {
+        if let Part::Unquoted(text) | Part::DoubleQuoted(text) | Part::Literal(text) = part {
+            if text.is_empty() {
+                return;
+            }
         }
+        self.0.push(part);
     }
+}
 
-    pub fn is_op(&self) -> bool {
-        match self {
-            Self::Op(_) => true,
-            _ => false,
-        }
+#[derive(Debug)]
+pub struct Lexer<'a> {
+    input: &'a str,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input }
     }
 
-    pub fn unwrap(self) -> &'a str {
-        *self
+    /// The input not yet split into tokens.
+    pub fn rest(&self) -> &'a str {
+        self.input
     }
-}
 
-impl<'a> Deref for Token<'a> {
-    type Target = &'a str;
+    /// Splits the word at the start of the input off. Quotes may appear
+    /// anywhere in a word, and whatever they enclose is taken literally
+    /// instead of ending the word at whitespace or an operator. Outside
+    /// quotes, a backslash makes the char after it literal in the same way,
+    /// and a `$(...)` is kept whole however many words it contains.
+    fn word(&mut self) -> Token<'a> {
+        let input = self.input;
+        let mut word = Word::default();
+        // where the unquoted text not yet added to `word` begins
+        let mut run = 0;
+        let mut i = 0;
+        while let Some(c) = input[i..].chars().next() {
+            if c.is_whitespace() || op_at(&input[i..]).is_some() {
+                break;
+            }
+            match c {
+                '\'' | '"' => {
+                    word.push(Part::Unquoted(&input[run..i]));
+                    let quoted = &input[i + 1..];
+                    let end = if c == '\'' {
+                        quoted.find('\'').inspect(|&end| word.push(Part::Literal(&quoted[..end])))
+                    } else {
+                        double_quoted(quoted, &mut word)
+                    };
+                    let Some(end) = end else {
+                        self.input = "";
+                        return Token::Invalid("unterminated quote");
+                    };
+                    i += end + 2;
+                    run = i;
+                }
+                // a trailing backslash has nothing to escape, so it stays
+                '\\' => match input[i + 1..].chars().next() {
+                    Some(escaped) => {
+                        word.push(Part::Unquoted(&input[run..i]));
+                        run = i + 1 + escaped.len_utf8();
+                        word.push(Part::Literal(&input[i + 1..run]));
+                        i = run;
+                    }
+                    None => i += 1,
+                },
+                '$' if input[i + 1..].starts_with('(') => {
+                    word.push(Part::Unquoted(&input[run..i]));
+                    let Some(close) = substitution_end(&input[i + 2..]).map(|end| i + 2 + end) else {
+                        self.input = "";
+                        return Token::Invalid("unterminated command substitution");
+                    };
+                    word.push(Part::Command(&input[i + 2..close]));
+                    i = close + 1;
+                    run = i;
+                }
+                _ => i += c.len_utf8(),
+            }
+        }
+        word.push(Part::Unquoted(&input[run..i]));
+        self.input = &input[i..];
+        Token::Arg(word)
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        match self {
-            Self::Arg(s) => s,
-            Self::Op(s) => s,
+/// Adds the double-quoted text at the start of `quoted` to `word`, returning
+/// the index of the closing quote. A backslash only escapes the chars that
+/// are special inside double quotes, and is kept before any other. A
+/// `$(...)` may contain quotes of its own.
+fn double_quoted<'a>(quoted: &'a str, word: &mut Word<'a>) -> Option<usize> {
+    let mut run = 0;
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                word.push(Part::DoubleQuoted(&quoted[run..i]));
+                return Some(i);
+            }
+            '\\' => {
+                if let Some((escaped, '"' | '\\' | '$' | '`')) = chars.clone().next() {
+                    word.push(Part::DoubleQuoted(&quoted[run..i]));
+                    // every escapable char is a single byte
+                    run = escaped + 1;
+                    word.push(Part::Literal(&quoted[escaped..run]));
+                    chars.next();
+                }
+            }
+            '$' if quoted[i + 1..].starts_with('(') => {
+                word.push(Part::DoubleQuoted(&quoted[run..i]));
+                let close = i + 2 + substitution_end(&quoted[i + 2..])?;
+                word.push(Part::QuotedCommand(&quoted[i + 2..close]));
+                run = close + 1;
+                while chars.next().is_some_and(|(j, _)| j < close) {}
+            }
+            _ => (),
         }
     }
+    None
 }
 
-impl<'a> DerefMut for Token<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        match self {
-            Self::Arg(s) => s,
-            Self::Op(s) => s,
+/// The index of the `)` that ends the command substitution whose commands
+/// `text` starts with. Parentheses nest, so an inner `$(...)` doesn't end
+/// it, and quoted or escaped ones don't count.
+fn substitution_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            '\\' => {
+                chars.next();
+            }
+            '\'' => {
+                chars.find(|&(_, c)| c == '\'')?;
+            }
+            '"' => {
+                loop {
+                    match chars.next()?.1 {
+                        '"' => break,
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
         }
     }
+    None
 }
 
-#[derive(Debug)]
-pub struct Lexer<'a> {
-    input: &'a str,
-    delim: Option<Token<'a>>,
+/// `line` without its final backslash, if that backslash continues the
+/// command on the next line: it must be outside single quotes and
+/// comments, and not itself escaped.
+pub fn strip_continuation(line: &str) -> Option<&str> {
+    let mut lexer = Lexer::new(line);
+    loop {
+        if lexer.rest().trim_start().starts_with('#') {
+            return None;
+        }
+        if lexer.next().is_none() {
+            break;
+        }
+    }
+    let mut quote = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // checking for a next char also skips the char being escaped
+            '\\' if quote != Some('\'') && chars.next().is_none() => return Some(&line[..i]),
+            '\'' | '"' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            _ => (),
+        }
+    }
+    None
 }
 
-impl<'a> Lexer<'a> {
-    pub fn new(input: &'a str) -> Self {
-        Self {
-            input,
-            delim: None,
+/// `text` as a word that lexes back to exactly `text`: unchanged if it is
+/// made only of chars that are never special, and single-quoted otherwise.
+/// Works on bytes, so text that isn't UTF-8 is quoted as is.
+pub fn quote(text: &[u8]) -> Vec<u8> {
+    let plain = |b: &u8| b.is_ascii_alphanumeric() || b"_-./:,+@%=".contains(b);
+    if !text.is_empty() && text.iter().all(plain) {
+        return text.to_vec();
+    }
+    let mut quoted = vec![b'\''];
+    for &b in text {
+        if b == b'\'' {
+            // close the quotes, add an escaped quote and reopen them
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
         }
     }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// The operator `input` starts with, if any.
+fn op_at(input: &str) -> Option<&'static str> {
+    RESERVED_OP.iter().copied().find(|op| input.starts_with(op))
+}
+
+/// The stderr redirect `input` starts with, if any. These are only
+/// operators where a word would start, so `a2>f` is still the argument
+/// `a2` with its stdout redirected.
+fn error_op_at(input: &str) -> Option<&'static str> {
+    // "2>>" first so it isn't read as "2>" and an argument
+    ["2>>", "2>"].into_iter().find(|op| input.starts_with(op))
 }
 
 impl<'a> Iterator for Lexer<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(token) = self.delim.take() {
-            Some(token)
-        } else if self.input.is_empty() {
+        self.input = self.input.trim_start();
+        // a `#` that starts a word comments out the rest of the line, while
+        // one inside a word or quotes is an ordinary char
+        if self.input.starts_with('#') {
+            self.input = "";
+        }
+        if self.input.is_empty() {
             None
+        } else if let Some(op) = op_at(self.input).or_else(|| error_op_at(self.input)) {
+            let slice;
+            (slice, self.input) = self.input.split_at(op.len());
+            Some(Token::Op(slice))
         } else {
-            let mut found = None;
-            'chars: for i in 0..self.input.len() {
-                match self.input.get(i..) {
-                    Some(s) if s.starts_with(|c: char| c.is_whitespace()) => {
-                        found = Some((i, None));
-                        break;
-                    }
-                    _ => (),
-                }
-                for &op in RESERVED_OP {
-                    match self.input.get(i..i + op.len()) {
-                        Some(slice) if slice == op => {
-                            found = Some((i, Some(slice)));
-                            break 'chars;
-                        }
-                        _ => (),
-                    }
-                }
-            }
-            if let Some((i, tok_typ)) = found {
-                let mut slice;
-                (slice, self.input) = self.input.split_at(i);
-                if let Some(tok) = tok_typ {
-                    // delim
-                    if slice.is_empty() {
-                        // delim at start
-                        (slice, self.input) = self.input.split_at(tok.len());
-                        Some(Token::Op(slice))
-                    } else {
-                        // delim somewhere
-                        let delim_slice;
-                        (delim_slice, self.input) = self.input.split_at(tok.len());
-                        self.delim = Some(Token::Op(delim_slice));
-                        Some(Token::Arg(slice))
-                    }
-                } else {
-                    // whitespace
-                    if let Some((i, _)) = self.input.char_indices().find(|(_, c)| !c.is_whitespace()) {
-                        (_, self.input) = self.input.split_at(i);
-                    } else {
-                        self.input = "";
-                    }
-                    if slice.is_empty() {
-                        // whitespace at start
-                        self.next()
-                    } else {
-                        Some(Token::Arg(slice))
-                    }
-                }
-            } else {
-                // nothing found
-                let slice = self.input;
-                self.input = "";
-                Some(Token::Arg(slice))
+            Some(self.word())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token<'_>> {
+        Lexer::new(line).collect()
+    }
+
+    fn arg<'a>(parts: &[Part<'a>]) -> Token<'a> {
+        Token::Arg(Word(parts.to_vec()))
+    }
+
+    #[test]
+    fn quotes_keep_special_chars_in_one_word() {
+        use Part::*;
+        assert_eq!(
+            tokens("echo \"hello world\" 'a;b' \"x > y | z\""),
+            [
+                arg(&[Unquoted("echo")]),
+                arg(&[DoubleQuoted("hello world")]),
+                arg(&[Literal("a;b")]),
+                arg(&[DoubleQuoted("x > y | z")]),
+            ]
+        );
+        assert_eq!(
+            tokens("a\"b c\"'d|e'f >o"),
+            [
+                arg(&[Unquoted("a"), DoubleQuoted("b c"), Literal("d|e"), Unquoted("f")]),
+                Token::Op(">"),
+                arg(&[Unquoted("o")]),
+            ]
+        );
+        assert_eq!(tokens("'' \"\""), [arg(&[]), arg(&[])]);
+    }
+
+    #[test]
+    fn stderr_redirects_only_start_a_word() {
+        use Part::*;
+        assert_eq!(
+            tokens("a2>f 2>>g 2>h"),
+            [
+                arg(&[Unquoted("a2")]),
+                Token::Op(">"),
+                arg(&[Unquoted("f")]),
+                Token::Op("2>>"),
+                arg(&[Unquoted("g")]),
+                Token::Op("2>"),
+                arg(&[Unquoted("h")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_unterminated_quote_ends_the_line_with_an_error() {
+        use Part::*;
+        assert_eq!(tokens("echo 'a; b"), [arg(&[Unquoted("echo")]), Token::Invalid("unterminated quote")]);
+        assert_eq!(tokens("echo \"a\\\""), [arg(&[Unquoted("echo")]), Token::Invalid("unterminated quote")]);
+    }
+
+    #[test]
+    fn double_quotes_escape_only_their_special_chars() {
+        use Part::*;
+        assert_eq!(
+            tokens(r#""a\"b\\c\$d\`e\xf""#),
+            [arg(&[
+                DoubleQuoted("a"),
+                Literal("\""),
+                DoubleQuoted("b"),
+                Literal("\\"),
+                DoubleQuoted("c"),
+                Literal("$"),
+                DoubleQuoted("d"),
+                Literal("`"),
+                DoubleQuoted("e\\xf"),
+            ])]
+        );
+    }
+
+    #[test]
+    fn substitutions_end_at_their_own_parenthesis() {
+        use Part::*;
+        assert_eq!(tokens("$(a $(b) c)d"), [arg(&[Command("a $(b) c"), Unquoted("d")])]);
+        assert_eq!(tokens(r#"$(echo ')' ")" \))"#), [arg(&[Command(r#"echo ')' ")" \)"#)])]);
+        assert_eq!(
+            tokens(r#""x$(echo ")")y""#),
+            [arg(&[DoubleQuoted("x"), QuotedCommand(r#"echo ")""#), DoubleQuoted("y")])]
+        );
+        assert_eq!(
+            tokens("echo $(a; b"),
+            [arg(&[Unquoted("echo")]), Token::Invalid("unterminated command substitution")]
+        );
+    }
+
+    #[test]
+    fn a_backslash_makes_the_next_char_literal() {
+        use Part::*;
+        assert_eq!(tokens(r"foo\ bar"), [arg(&[Unquoted("foo"), Literal(" "), Unquoted("bar")])]);
+        assert_eq!(tokens(r"echo \;"), [arg(&[Unquoted("echo")]), arg(&[Literal(";")])]);
+        assert_eq!(tokens(r#"\"a\'"#), [arg(&[Literal("\""), Unquoted("a"), Literal("'")])]);
+        assert_eq!(tokens(r"a\"), [arg(&[Unquoted(r"a\")])]);
+    }
+
+    #[test]
+    fn only_an_unescaped_final_backslash_continues_a_line() {
+        assert_eq!(strip_continuation(r"echo a \"), Some("echo a "));
+        assert_eq!(strip_continuation(r#"echo "a \"#), Some(r#"echo "a "#));
+        assert_eq!(strip_continuation(r"echo a \\"), None);
+        assert_eq!(strip_continuation(r"echo 'a \"), None);
+        assert_eq!(strip_continuation(r"echo \ a"), None);
+        assert_eq!(strip_continuation(""), None);
+    }
+
+    #[test]
+    fn a_hash_comments_out_the_line_only_at_the_start_of_a_word() {
+        use Part::*;
+        assert_eq!(tokens("# all of it"), []);
+        assert_eq!(tokens("echo a # b; c"), [arg(&[Unquoted("echo")]), arg(&[Unquoted("a")])]);
+        assert_eq!(tokens("echo a;# b"), [arg(&[Unquoted("echo")]), arg(&[Unquoted("a")]), Token::Op(";")]);
+        assert_eq!(
+            tokens(r##"a#b '#c' "#d" \#e"##),
+            [
+                arg(&[Unquoted("a#b")]),
+                arg(&[Literal("#c")]),
+                arg(&[DoubleQuoted("#d")]),
+                arg(&[Literal("#"), Unquoted("e")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_backslash_in_a_comment_does_not_continue_the_line() {
+        assert_eq!(strip_continuation(r"echo a # c \"), None);
+        assert_eq!(strip_continuation(r"# \"), None);
+        assert_eq!(strip_continuation(r"echo a#b \"), Some("echo a#b "));
+        assert_eq!(strip_continuation(r"echo '# c' \"), Some("echo '# c' "));
+    }
+
+    #[test]
+    fn quoted_text_lexes_back_to_itself() {
+        for text in ["plain", "", "a b", "it's", "$HOME", r"a\b", "#x", "x;y", "\"", "''"] {
+            let quoted = String::from_utf8(quote(text.as_bytes())).unwrap();
+            let [Token::Arg(word)] = &tokens(&quoted)[..] else {
+                panic!("{} did not lex as one word", quoted);
+            };
+            let lexed: String = word
+                .0
+                .iter()
+                .map(|part| match part {
+                    Part::Unquoted(text) | Part::Literal(text) => *text,
+                    part => panic!("{:?} in {} would expand", part, quoted),
+                })
+                .collect();
+            assert_eq!(lexed, text);
+        }
+        assert_eq!(quote(b"a-b.c"), b"a-b.c");
+        assert_eq!(quote(b"\xff"), b"'\xff'");
+    }
+
+    #[test]
+    fn a_long_line_lexes_like_its_pieces() {
+        for piece in ["echo a ", "x>>y ", "'q w' ", "\"$X\"\\ z; ", "a&&b||c|d<e>+f "] {
+            let line = piece.repeat(10_000);
+            let expected: Vec<_> = (0..10_000).flat_map(|_| tokens(piece)).collect();
+            assert_eq!(tokens(&line), expected, "{}", piece);
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to see how
+    /// lexing time grows with line length; it should stay linear.
+    #[test]
+    #[ignore]
+    fn lexing_time_on_multi_megabyte_lines() {
+        for (name, piece) in [("words", "ab "), ("one word", "a"), ("operators", ">"), ("whitespace", " ")] {
+            for megabytes in [1, 4] {
+                let line = piece.repeat(megabytes * 1_000_000 / piece.len());
+                let start = Instant::now();
+                let count = Lexer::new(&line).count();
+                println!("{} MB of {}: {} tokens in {:?}", megabytes, name, count, start.elapsed());
             }
         }
     }
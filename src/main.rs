@@ -1,157 +1,2259 @@
 mod lexer;
 mod ast;
-use std::{io::{self, Write, BufRead, BufReader}, process::Command, env, fs::{File, OpenOptions}, path::PathBuf};
+mod expand;
+mod glob;
+mod signal;
+mod suggest;
+mod temp;
+use std::{cell::Cell, collections::BTreeMap, error::Error, ffi::{OsStr, OsString}, fmt, iter, io::{self, Write, BufRead, BufReader, IsTerminal}, process::{self, Child, ChildStdout, Command, ExitStatus, Stdio}, rc::Rc, thread, env, fs::{self, File, OpenOptions}, path::{self, Component, Path, PathBuf}, time::UNIX_EPOCH};
 
-use ast::{Expr, Cmd, Combinator};
-use lexer::Lexer;
+use ast::{AndOr, Expr, Cmd, CdMode, Combinator, Junction, Redirect, Simple, Stage};
+use lexer::{Lexer, Part, Token, Word};
 use colored::Colorize;
 
+/// How a command finished.
 pub enum ResultKind {
-    Exit,
-    Ok,
+    /// The shell should stop reading commands and exit with this status,
+    /// or with the last command's status if there is none.
+    Exit(Option<i32>),
+    /// The command finished with this exit status; zero means success.
+    Status(i32),
+}
+
+impl ResultKind {
+    pub const SUCCESS: Self = Self::Status(0);
 }
 
 type CommandResult = Result<ResultKind, io::Error>;
 
+/// Somewhere command output can go, which knows whether it is a terminal.
+pub trait Output: Write {
+    fn is_tty(&self) -> bool;
+
+    /// What a program can write to directly so its output arrives as it is
+    /// produced, or `None` if the shell has to capture it and pass it on.
+    fn stdio(&mut self) -> io::Result<Option<Stdio>>;
+}
+
+impl Output for io::Stdout {
+    fn is_tty(&self) -> bool {
+        self.is_terminal()
+    }
+
+    fn stdio(&mut self) -> io::Result<Option<Stdio>> {
+        // anything a builtin left buffered has to come first
+        self.flush()?;
+        Ok(Some(Stdio::inherit()))
+    }
+}
+
+impl Output for File {
+    fn is_tty(&self) -> bool {
+        self.is_terminal()
+    }
+
+    fn stdio(&mut self) -> io::Result<Option<Stdio>> {
+        self.try_clone().map(|file| Some(file.into()))
+    }
+}
+
 pub trait Runnable {
-    fn run(self, out: &mut impl Write) -> CommandResult;
+    fn run(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult;
 }
 
-impl<'a> Runnable for Expr<'a> {
-    fn run(self, out: &mut impl Write) -> CommandResult {
+/// The state a session carries from one command to the next.
+#[derive(Clone)]
+pub struct Shell {
+    flags: Flags,
+    /// The last command's status, which `$?` expands to and a bare `exit`
+    /// exits with.
+    pub status: i32,
+    /// Alias bodies by name, sorted so `alias` lists them in order.
+    aliases: BTreeMap<String, String>,
+    /// The status of the last `$(...)` in the command being expanded, which
+    /// a command made only of assignments finishes with, as in sh.
+    substituted: Cell<Option<i32>>,
+    /// The script or stream being read without a prompt, if any, and the
+    /// line of it being run, for error messages to point at.
+    source: Option<String>,
+    line: usize,
+    errors: Errors,
+}
+
+/// Where a shell reports errors. Clones share it, so a `$(...)` reports
+/// wherever the shell around it would.
+#[derive(Clone)]
+enum Errors {
+    Stderr,
+    /// The file a command's `2>` or `2>>` names, while it runs.
+    File(Rc<File>),
+    /// Kept for a test to read.
+    #[cfg(test)]
+    Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>),
+}
+
+impl Write for Errors {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stderr => io::stderr().write(buf),
+            Self::File(file) => (&**file).write(buf),
+            #[cfg(test)]
+            Self::Buffer(buffer) => buffer.borrow_mut().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stderr => io::stderr().flush(),
+            Self::File(file) => (&**file).flush(),
+            #[cfg(test)]
+            Self::Buffer(_) => Ok(()),
+        }
+    }
+}
+
+impl Errors {
+    /// Whether errors reach a terminal, which is the only place they're
+    /// styled.
+    fn is_terminal(&self) -> bool {
+        matches!(self, Self::Stderr) && io::stderr().is_terminal()
+    }
+
+    /// Where a program started now should write its stderr.
+    fn stdio(&self) -> io::Result<Stdio> {
         match self {
-            Self::NoOp => Ok(ResultKind::Ok),
-            Self::Seq(combinator, rest) => {
-                match combinator.run(out)? {
-                    ResultKind::Ok => rest.run(out),
-                    exit => Ok(exit),
+            Self::File(file) => Ok(file.try_clone()?.into()),
+            _ => Ok(Stdio::inherit()),
+        }
+    }
+}
+
+impl Shell {
+    fn new(flags: Flags) -> Self {
+        Self {
+            flags,
+            status: 0,
+            aliases: BTreeMap::new(),
+            substituted: Cell::new(None),
+            source: None,
+            line: 0,
+            errors: Errors::Stderr,
+        }
+    }
+
+    /// Reports a command that failed, along with a "did you mean" hint in
+    /// interactive mode when it names a program that doesn't exist.
+    fn report_error(&self, reason: &io::Error) -> io::Result<()> {
+        if reason.get_ref().is_some_and(|inner| inner.is::<Reported>()) {
+            return Ok(());
+        }
+        if self.source.is_none() && !self.flags.no_suggest {
+            suggest_command(&mut self.errors.clone(), reason)?;
+        }
+        self.report(reason)
+    }
+
+    /// Prints an error to stderr, prefixed with the source and line it came
+    /// from when not reading from the terminal.
+    fn report(&self, reason: impl fmt::Display) -> io::Result<()> {
+        let mut errors = self.errors.clone();
+        match &self.source {
+            Some(source) => {
+                let reason = format_args!("{}: line {}: {}", source, self.line, reason);
+                report_error(&mut errors, reason, self.errors.is_terminal())
+            }
+            None => report_error(&mut errors, reason, self.errors.is_terminal()),
+        }
+    }
+
+    /// What the command word `name` stands for if it is an alias. When the
+    /// body starts with another alias, that is replaced in turn, but never
+    /// an alias already being replaced, so `alias ls='ls -F'` still runs
+    /// `ls` and aliases that refer to each other can't loop forever.
+    fn unalias(&self, name: &str) -> Option<String> {
+        let mut body = self.aliases.get(name)?.clone();
+        let mut seen = vec![name.to_string()];
+        loop {
+            let mut lexer = Lexer::new(&body);
+            let Some(Token::Arg(Word(first))) = lexer.next() else {
+                break;
+            };
+            let [Part::Unquoted(first)] = first[..] else {
+                break;
+            };
+            if seen.iter().any(|name| name == first) {
+                break;
+            }
+            let Some(inner) = self.aliases.get(first) else {
+                break;
+            };
+            seen.push(first.to_string());
+            body = format!("{} {}", inner, lexer.rest());
+        }
+        Some(body)
+    }
+
+    /// Runs a parsed line and records the status it finished with. When the
+    /// line exits, that is the status the shell should exit with.
+    fn eval(&mut self, expr: Expr, out: &mut impl Output) -> CommandResult {
+        if self.flags.debug_ast {
+            eprintln!("{}", ast_dump(&expr));
+        }
+        let res = expr.run(self, out);
+        self.status = match &res {
+            Ok(ResultKind::Status(code)) => *code,
+            Ok(ResultKind::Exit(code)) => code.unwrap_or(self.status),
+            Err(reason) => error_status(reason),
+        };
+        res
+    }
+
+    /// Runs the commands of a `$(...)` and returns what they printed, less
+    /// any trailing newlines. As in a subshell, nothing they change outlives
+    /// them: not the status, aliases, variables or current directory, and
+    /// an `exit` only ends the substitution.
+    pub fn substitute(&self, source: &str) -> io::Result<OsString> {
+        let expr = Expr::try_from(Lexer::new(source)).map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, reason))?;
+        // the process has only one environment and directory, so those are
+        // put back by hand
+        let vars: Vec<_> = env::vars_os().collect();
+        let dir = env::current_dir();
+        let mut output = Vec::new();
+        let mut inner = self.clone();
+        let res = inner.eval(expr, &mut output);
+        restore_env(vars);
+        if let Ok(dir) = dir {
+            env::set_current_dir(dir)?;
+        }
+        // a failure inside only shows in the status; the command around it
+        // still gets whatever was printed
+        if let Err(reason) = res {
+            self.report_error(&reason)?;
+        }
+        self.substituted.set(Some(inner.status));
+        while output.last() == Some(&b'\n') {
+            output.pop();
+        }
+        Ok(os_string(output))
+    }
+}
+
+/// Makes the environment exactly `vars` again, as saved by `env::vars_os`.
+fn restore_env(vars: Vec<(OsString, OsString)>) {
+    let saved: BTreeMap<_, _> = vars.into_iter().collect();
+    for (name, _) in env::vars_os() {
+        if !saved.contains_key(&name) {
+            env::remove_var(name);
+        }
+    }
+    for (name, value) in saved {
+        if env::var_os(&name).as_ref() != Some(&value) {
+            env::set_var(name, value);
+        }
+    }
+}
+
+/// Program output as an OS string. Only Unix can hold arbitrary bytes, so
+/// elsewhere output that isn't UTF-8 is converted lossily.
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+impl<'a> Runnable for Expr<'a> {
+    fn run(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult {
+        for and_or in self.0 {
+            match and_or.run(shell, out) {
+                Ok(ResultKind::Status(_)) => {}
+                Ok(ResultKind::Exit(code)) => return Ok(ResultKind::Exit(code)),
+                // a command that couldn't run has failed like any other, and
+                // the rest of the line still runs
+                Err(reason) => {
+                    shell.report_error(&reason)?;
+                    shell.status = error_status(&reason);
                 }
             }
+            // Ctrl-C abandons the rest of the line, not just one pipeline
+            if signal::interrupted() {
+                return Ok(ResultKind::Status(signal::INTERRUPT_STATUS));
+            }
         }
+        // an empty line leaves the status as it was
+        Ok(ResultKind::Status(shell.status))
     }
 }
 
-impl<'a> Runnable for Combinator<'a> {
-    fn run(self, out: &mut impl Write) -> CommandResult {
-        match self {
-            Self::Identity(cmd) => cmd.run(out),
-            Self::Redirect(cmd, output) => {
-                let mut file = File::create(output)?;
-                cmd.run(&mut file)
+impl<'a> Runnable for AndOr<'a> {
+    fn run(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult {
+        let AndOr(first, rest) = self;
+        let mut chain = iter::once((Junction::And, first)).chain(rest).peekable();
+        let mut status = 0;
+        while let Some((junction, combinator)) = chain.next() {
+            let wanted = match junction {
+                Junction::And => status == 0,
+                Junction::Or => status != 0,
+            };
+            if !wanted {
+                continue;
+            }
+            if signal::interrupted() {
+                break;
             }
-            Self::RedirectAppend(cmd, output) => {
-                let mut file = OpenOptions::new().append(true).create(true).open(output)?;
-                cmd.run(&mut file)
+            status = match combinator.run(shell, out) {
+                Ok(ResultKind::Status(code)) => code,
+                Ok(ResultKind::Exit(code)) => return Ok(ResultKind::Exit(code)),
+                // a command that couldn't run has failed, which is exactly
+                // what a following `||` is there to handle
+                // never into `out`, which may be a `$(...)` being captured
+                Err(reason) if chain.peek().is_some() => {
+                    shell.report_error(&reason)?;
+                    error_status(&reason)
+                }
+                Err(reason) => return Err(reason),
+            };
+            // so the next command's `$?` sees it
+            shell.status = status;
+        }
+        Ok(ResultKind::Status(status))
+    }
+}
+
+impl Output for Vec<u8> {
+    fn is_tty(&self) -> bool {
+        false
+    }
+
+    fn stdio(&mut self) -> io::Result<Option<Stdio>> {
+        Ok(None)
+    }
+}
+
+/// What a command reads as its stdin.
+enum StageInput {
+    /// The shell's own stdin, usually the terminal, so programs like editors
+    /// and pagers can read keystrokes.
+    Inherit,
+    /// The stdout of the previous program in a pipeline.
+    Child(ChildStdout),
+    /// Output already produced by a builtin earlier in a pipeline.
+    Bytes(Vec<u8>),
+    /// A file redirected in with `<`.
+    File(File),
+}
+
+/// A program name that matched nothing on `PATH`.
+#[derive(Debug)]
+pub struct CommandNotFound(pub String);
+
+impl fmt::Display for CommandNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: command not found", self.0)
+    }
+}
+
+impl Error for CommandNotFound {}
+
+/// A failure already reported where its command's errors were redirected,
+/// with the status it finished with, so it isn't reported again.
+#[derive(Debug)]
+struct Reported(i32);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed with status {}", self.0)
+    }
+}
+
+impl Error for Reported {}
+
+/// The status of a finished program: its exit code, or 128 plus the signal
+/// number if a signal killed it.
+fn exit_status(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return wrap_status((128 + signal).into());
+        }
+    }
+    status.code().map_or(1, |code| wrap_status(code.into()))
+}
+
+/// Wraps `code` into the 0 to 255 a status can hold, the way the OS would
+/// truncate it, so `exit 256` is 0 and `exit -1` is 255. Every status the
+/// shell reports or exits with goes through here.
+fn wrap_status(code: i64) -> i32 {
+    code.rem_euclid(256) as i32
+}
+
+/// The status of a command that failed with `reason` instead of finishing:
+/// 127 when the program doesn't exist, as in other shells, otherwise 1.
+fn error_status(reason: &io::Error) -> i32 {
+    let Some(inner) = reason.get_ref() else {
+        return 1;
+    };
+    if let Some(Reported(status)) = inner.downcast_ref() {
+        *status
+    } else if inner.is::<CommandNotFound>() {
+        127
+    } else {
+        1
+    }
+}
+
+/// Spawns `process`, reporting a bare program name that isn't on `PATH` as
+/// `CommandNotFound` rather than a generic missing-file error.
+fn spawn(process: &mut Command) -> io::Result<Child> {
+    process.spawn().map_err(|reason| {
+        let program = process.get_program().to_string_lossy();
+        if reason.kind() == io::ErrorKind::NotFound && !program.contains(path::is_separator) {
+            io::Error::new(io::ErrorKind::NotFound, CommandNotFound(program.into_owned()))
+        } else {
+            reason
+        }
+    })
+}
+
+/// Spawns `process` reading from `input`.
+fn spawn_with(process: &mut Command, input: StageInput) -> io::Result<Child> {
+    match input {
+        StageInput::Inherit => spawn(process.stdin(Stdio::inherit())),
+        StageInput::Child(stdout) => spawn(process.stdin(Stdio::from(stdout))),
+        StageInput::File(file) => spawn(process.stdin(Stdio::from(file))),
+        StageInput::Bytes(bytes) => {
+            let mut child = spawn(process.stdin(Stdio::piped()))?;
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            // written from another thread so a child that fills its own
+            // output before reading everything cannot deadlock the shell;
+            // a child that exits early just closes the pipe on us
+            thread::spawn(move || {
+                let _ = stdin.write_all(&bytes);
+            });
+            Ok(child)
+        }
+    }
+}
+
+impl<'a> Runnable for Combinator<'a> {
+    fn run(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult {
+        let piped = self.stages.len() > 1;
+        let mut children = Vec::new();
+        let res = self.run_stages(shell, &mut children, out);
+        // reap every stage even if a later one failed; their output pipes
+        // are closed by now, so none can block forever
+        for mut child in children {
+            child.wait()?;
+        }
+        match res {
+            // exiting in the last stage ends only the pipeline, as it does
+            // in every other stage
+            Ok(ResultKind::Exit(code)) if piped => Ok(ResultKind::Status(code.unwrap_or(shell.status))),
+            res => res,
+        }
+    }
+}
+
+impl<'a> Combinator<'a> {
+    /// Starts each stage reading what the one before it wrote, adding the
+    /// programs started to `children`, and runs the last one to the end.
+    fn run_stages(self, shell: &mut Shell, children: &mut Vec<Child>, out: &mut impl Output) -> CommandResult {
+        let mut stages = self.stages;
+        let Stage { cmd, input: source, errors } = stages.pop().expect("a pipeline has a command");
+        let mut input = StageInput::Inherit;
+        for stage in stages {
+            input = redirected_input(shell, stage.input, input)?;
+            let child;
+            (input, child) = errors_to(shell, stage.errors, |shell| {
+                expanded(shell, &stage.cmd, |shell, cmd| cmd.start(shell, input))
+            })?;
+            children.extend(child);
+        }
+        let input = redirected_input(shell, source, input)?;
+        errors_to(shell, errors, |shell| {
+            let Some((redirect, output)) = self.output else {
+                return expanded(shell, &cmd, |shell, cmd| cmd.run_from(shell, input, out));
+            };
+            let output = expand::word(&output, shell)?;
+            match redirect {
+                Redirect::Truncate | Redirect::Append => {
+                    let file = open_redirect(redirect, &output)?;
+                    expanded(shell, &cmd, |shell, cmd| cmd.run_into(shell, input, file))
+                }
+                Redirect::Insert => insert_into(shell, &cmd, input, &output),
             }
-            Self::RedirectInsert(cmd, output) => {
-                let mut orig = File::open(output)?;
-                let dir = PathBuf::from("myshell_tmp.txt");
-                let mut temp = File::create(&dir)?;
+        })
+    }
+}
 
-                let res = cmd.run(&mut temp);
+/// Runs `cmd` with its output inserted at the start of the file `output`,
+/// by writing it and then the file's old contents to a temp file that
+/// replaces it.
+fn insert_into(shell: &mut Shell, cmd: &Simple, input: StageInput, output: &OsStr) -> CommandResult {
+    // there is nothing to insert ahead of, and the device itself
+    // must never be replaced by the temp file
+    if is_null_device(output) {
+        return expanded(shell, cmd, |shell, cmd| cmd.run_into(shell, input, null_sink()?));
+    }
+    let mut orig = File::open(output)?;
+    // next to the target, so the final rename can't cross filesystems
+    let target = Path::new(output);
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let (temp_path, mut temp) = temp::file(&target.with_file_name(format!("{}.XXXXXX", name)))?;
+    let inserted = (|| -> CommandResult {
+        let res = expanded(shell, cmd, |shell, cmd| cmd.run_into(shell, input, temp.try_clone()?))?;
+        // the command's output left the shared offset at its end
+        io::copy(&mut orig, &mut temp)?;
+        fs::set_permissions(&temp_path, orig.metadata()?.permissions())?;
+        fs::rename(&temp_path, output)?;
+        Ok(res)
+    })();
+    if inserted.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    inserted
+}
 
-                io::copy(&mut orig, &mut temp)?;
-                std::fs::remove_file(output)?;
-                std::fs::rename(&dir, &output)?;
+/// Opens the file a `>`, `>>`, `2>` or `2>>` writes to, which for the null
+/// device is always the platform's own.
+fn open_redirect(redirect: Redirect, path: &OsStr) -> io::Result<File> {
+    if is_null_device(path) {
+        null_sink()
+    } else if redirect == Redirect::Append {
+        OpenOptions::new().append(true).create(true).open(path)
+    } else {
+        File::create(path)
+    }
+}
+
+/// Runs `run` with errors going to the file `redirect` names, if there is
+/// one: both what programs started write to stderr and the shell's report
+/// if the command fails, which is then not reported again.
+fn errors_to<T>(
+    shell: &mut Shell,
+    redirect: Option<(Redirect, Word)>,
+    run: impl FnOnce(&mut Shell) -> io::Result<T>,
+) -> io::Result<T> {
+    let Some((redirect, target)) = redirect else {
+        return run(shell);
+    };
+    let file = open_redirect(redirect, &expand::word(&target, shell)?)?;
+    let errors = std::mem::replace(&mut shell.errors, Errors::File(Rc::new(file)));
+    let res = run(shell).or_else(|reason| {
+        shell.report_error(&reason)?;
+        Err(io::Error::new(reason.kind(), Reported(error_status(&reason))))
+    });
+    shell.errors = errors;
+    res
+}
 
-                res
+/// What a stage reads: the file its `<` names, which replaces whatever the
+/// stage before it wrote, or else `input`.
+fn redirected_input(shell: &Shell, source: Option<Word>, input: StageInput) -> io::Result<StageInput> {
+    match source {
+        Some(source) => Ok(StageInput::File(File::open(expand::word(&source, shell)?)?)),
+        None => Ok(input),
+    }
+}
+
+/// Expands the words of `cmd` and hands the command they make to `run`.
+/// Builtins are only recognized after expansion, so a variable can name
+/// one, and a glob can expand to any number of arguments. Assignments are
+/// made after the words are expanded, and are undone once the command
+/// finishes unless there is no command. An alias as the command word is
+/// replaced by the words of its body first.
+fn expanded<T>(shell: &mut Shell, cmd: &Simple, run: impl FnOnce(&mut Shell, Cmd) -> io::Result<T>) -> io::Result<T> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidInput, reason);
+    shell.substituted.set(None);
+    // only a plain unquoted word is looked up, so `\\ls` skips an alias
+    let body = match cmd.words.first().map(|word| word.0.as_slice()) {
+        Some([Part::Unquoted(name)]) => shell.unalias(name),
+        _ => None,
+    };
+    let body_words = match &body {
+        Some(body) => ast::alias_body(body).map_err(invalid)?,
+        None => Vec::new(),
+    };
+    let mut words = Vec::new();
+    for word in body_words.iter().chain(&cmd.words[usize::from(body.is_some())..]) {
+        words.extend(expand::fields(word, shell)?);
+    }
+    let parsed = Cmd::try_from(words.as_slice()).map_err(invalid)?;
+    let mut previous = Vec::new();
+    let res = (|| {
+        // each value can use the ones assigned before it
+        for (name, value) in &cmd.assignments {
+            let value = expand::word(value, shell)?;
+            previous.push((name, env::var_os(name)));
+            env::set_var(name, value);
+        }
+        run(shell, parsed)
+    })();
+    if !words.is_empty() {
+        for (name, value) in previous.into_iter().rev() {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
             }
         }
     }
+    res
 }
 
 impl<'a> Runnable for Cmd<'a> {
-    fn run(self, out: &mut impl Write) -> CommandResult {
+    fn run(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult {
+        self.run_from(shell, StageInput::Inherit, out)
+    }
+}
+
+impl<'a> Cmd<'a> {
+    fn run_from(self, shell: &mut Shell, input: StageInput, out: &mut impl Output) -> CommandResult {
+        match self.process(shell) {
+            Some(process) => {
+                let mut process = process?;
+                if let Some(stdout) = out.stdio()? {
+                    let status = spawn_with(process.stdout(stdout), input)?.wait()?;
+                    return Ok(ResultKind::Status(exit_status(status)));
+                }
+                // capture stdout so it reaches `out` wherever that points
+                let output = spawn_with(process.stdout(Stdio::piped()), input)?.wait_with_output()?;
+                out.write_all(output.stdout.as_slice())?;
+                Ok(ResultKind::Status(exit_status(output.status)))
+            }
+            None => self.run_builtin(shell, out),
+        }
+    }
+
+    /// Starts the command as a non-final pipeline stage, returning what the
+    /// next stage should read and the child to reap once the pipeline ends.
+    fn start(self, shell: &mut Shell, input: StageInput) -> io::Result<(StageInput, Option<Child>)> {
+        match self.process(shell) {
+            Some(process) => {
+                let mut child = spawn_with(process?.stdout(Stdio::piped()), input)?;
+                let stdout = child.stdout.take().expect("stdout is piped");
+                Ok((StageInput::Child(stdout), Some(child)))
+            }
+            None => {
+                // builtins don't read stdin, and exiting inside a pipe stage
+                // doesn't end the shell
+                let mut buffer = Vec::new();
+                self.run_builtin(shell, &mut buffer)?;
+                Ok((StageInput::Bytes(buffer), None))
+            }
+        }
+    }
+
+    /// Runs a builtin. Output is written a whole line at a time with
+    /// `writeln!`, so every line ends in exactly one `\n` whether it goes to
+    /// the terminal, a pipe or a redirect; only output that is explicitly
+    /// not line-based (like `clear` or `echo -n`) omits it.
+    fn run_builtin(self, shell: &mut Shell, out: &mut impl Output) -> CommandResult {
         match self {
-            Cmd::NoOp => Ok(ResultKind::Ok),
+            Cmd::NoOp => Ok(ResultKind::Status(shell.substituted.get().unwrap_or(0))),
             Cmd::Pwd => {
-                let dir = env::current_dir()?;
-                write!(out, "{}\n", dir.display())?;
-                Ok(ResultKind::Ok)
+                let dir = logical_cwd()?;
+                writeln!(out, "{}", dir.display())?;
+                Ok(ResultKind::SUCCESS)
             },
-            Cmd::Cd(opt_path) => {
+            Cmd::Cd(mode, Some(path)) if path == "-" => {
+                let previous = env::var_os("OLDPWD").ok_or_else(|| io::Error::other("cd: OLDPWD not set"))?;
+                change_dir(Path::new(&previous), mode)?;
+                // as in bash, so it's clear where `-` led
+                writeln!(out, "{}", logical_cwd()?.display())?;
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Cd(mode, opt_path) => {
                 if let Some(path) = opt_path {
-                    env::set_current_dir(path)?;
-                    Ok(ResultKind::Ok)
+                    change_dir(Path::new(path), mode)?;
                 } else {
-                    env::set_current_dir(
-                        env::var("HOME").map_err(
-                            |_| io::Error::new(io::ErrorKind::Other, "could not locate home directory")
-                        )?
+                    let home = env::var_os("HOME").ok_or_else(
+                        || io::Error::other("could not locate home directory")
                     )?;
-                    Ok(ResultKind::Ok)
+                    change_dir(Path::new(&home), mode)?;
                 }
+                Ok(ResultKind::SUCCESS)
             }
-            Cmd::Other(cmd, args) => {
-                let output = Command::new(cmd)
-                    .args(args)
-                    .spawn()?
-                    .wait_with_output()?;
-                out.write_all(output.stdout.as_slice())?;
-                Ok(ResultKind::Ok)
+            Cmd::Clear => {
+                if out.is_tty() {
+                    write!(out, "\x1b[2J\x1b[H")?;
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Echo { newline, args } => {
+                // written as raw bytes, so arguments that aren't UTF-8 survive
+                let mut line = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        line.push(b' ');
+                    }
+                    line.extend_from_slice(arg.as_encoded_bytes());
+                }
+                if newline {
+                    line.push(b'\n');
+                }
+                out.write_all(&line)?;
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Export(vars) if vars.is_empty() => {
+                let mut vars: Vec<_> = env::vars_os().collect();
+                vars.sort();
+                // in a form that can be run again to recreate them
+                for (name, value) in vars {
+                    let mut line = b"export ".to_vec();
+                    line.extend_from_slice(name.as_encoded_bytes());
+                    line.push(b'=');
+                    line.extend(lexer::quote(value.as_encoded_bytes()));
+                    line.push(b'\n');
+                    out.write_all(&line)?;
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Export(vars) => {
+                // every variable is already in the environment, so only
+                // those given a value need to change
+                for (name, value) in vars {
+                    if let Some(value) = value {
+                        env::set_var(name, value);
+                    }
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Alias(aliases) if aliases.is_empty() => {
+                for (name, body) in &shell.aliases {
+                    write_alias(out, name, body)?;
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Alias(aliases) => {
+                for (name, body) in aliases {
+                    match body {
+                        Some(body) => {
+                            // checked now so a bad body is reported where it was written
+                            ast::alias_body(body).map_err(|reason| io::Error::new(io::ErrorKind::InvalidInput, reason))?;
+                            shell.aliases.insert(name.to_string(), body.to_string());
+                        }
+                        None => {
+                            let body = shell.aliases.get(name).ok_or_else(|| alias_not_found("alias", name))?;
+                            write_alias(out, name, body)?;
+                        }
+                    }
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Unalias { all, names } => {
+                if all {
+                    shell.aliases.clear();
+                }
+                for name in names {
+                    shell.aliases.remove(name).ok_or_else(|| alias_not_found("unalias", name))?;
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Unset(names) => {
+                for name in names {
+                    env::remove_var(name);
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Cdf(path) => {
+                let path = Path::new(path);
+                if fs::metadata(path)?.is_dir() {
+                    change_dir(path, CdMode::Logical)?;
+                } else {
+                    change_dir(path.parent().unwrap_or(path), CdMode::Logical)?;
+                }
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Finfo(paths) => {
+                let mut failures = Vec::new();
+                for path in paths {
+                    match file_info(Path::new(path)) {
+                        Ok(info) => writeln!(out, "{}\t{}", Path::new(path).display(), info)?,
+                        Err(reason) => failures.push((path, reason)),
+                    }
+                }
+                match failures.first() {
+                    None => Ok(ResultKind::SUCCESS),
+                    Some((_, first)) => {
+                        let message = failures.iter()
+                            .map(|(path, reason)| format!("finfo: {}: {}", Path::new(path).display(), reason))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        Err(io::Error::new(first.kind(), message))
+                    }
+                }
+            }
+            Cmd::Mktemp { dir, template } => {
+                let template = template.map_or_else(|| env::temp_dir().join("chitin.XXXXXXXXXX"), PathBuf::from);
+                let path = if dir {
+                    temp::dir(&template)?
+                } else {
+                    temp::file(&template)?.0
+                };
+                writeln!(out, "{}", path.display())?;
+                Ok(ResultKind::SUCCESS)
+            }
+            Cmd::Other(..) | Cmd::Nice(..) => unreachable!("external commands are spawned, not run as builtins"),
+            Cmd::Exit(code) => Ok(ResultKind::Exit(code.map(wrap_status))),
+            Cmd::ExitNonNumeric(arg) => {
+                shell.report(format_args!("exit: {}: numeric argument required", arg.to_string_lossy()))?;
+                Ok(ResultKind::Exit(Some(2)))
             }
-            Cmd::Exit => Ok(ResultKind::Exit),
         }
     }
 }
 
-fn interactive() -> io::Result<()> {
+/// Prints an alias as an `alias` command that would define it again.
+fn write_alias(out: &mut impl Output, name: &str, body: &str) -> io::Result<()> {
+    let mut line = format!("alias {}=", name).into_bytes();
+    line.extend(lexer::quote(body.as_bytes()));
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+fn alias_not_found(builtin: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: {}: not found", builtin, name))
+}
+
+/// Whether a redirection target names the null device. `/dev/null` is
+/// accepted on every platform so scripts stay portable; Windows also has `NUL`.
+fn is_null_device(path: &OsStr) -> bool {
+    path == "/dev/null" || (cfg!(windows) && path.eq_ignore_ascii_case("nul"))
+}
+
+/// Opens the platform's null device as an always-writable sink.
+fn null_sink() -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .open(if cfg!(windows) { "NUL" } else { "/dev/null" })
+}
+
+impl<'a> Cmd<'a> {
+    /// Runs the command with its output going to `file`. External commands
+    /// get the file as their stdout directly, so their output is never
+    /// buffered in the shell.
+    fn run_into(self, shell: &mut Shell, input: StageInput, mut file: File) -> CommandResult {
+        match self.process(shell) {
+            Some(process) => {
+                let status = spawn_with(process?.stdout(Stdio::from(file)), input)?.wait()?;
+                Ok(ResultKind::Status(exit_status(status)))
+            }
+            None => self.run_builtin(shell, &mut file),
+        }
+    }
+
+    /// The process to spawn for commands that run an external program, or
+    /// `None` for builtins. Its stderr goes wherever the shell's errors do.
+    fn process(&self, shell: &Shell) -> Option<io::Result<Command>> {
+        let (cmd, args) = match self {
+            Cmd::Other(cmd, args) | Cmd::Nice(_, cmd, args) => (cmd, args),
+            _ => return None,
+        };
+        let build = || {
+            let mut process = Command::new(cmd);
+            process.args(args).stderr(shell.errors.stdio()?);
+            if let Cmd::Nice(adjustment, ..) = self {
+                niced(&mut process, *adjustment)?;
+            }
+            Ok(process)
+        };
+        Some(build())
+    }
+}
+
+/// Makes `process` run with its niceness raised by `adjustment`, failing the
+/// spawn if the change is not permitted.
+#[cfg(unix)]
+fn niced(process: &mut Command, adjustment: i32) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: getpriority and setpriority are async-signal-safe and touch
+    // only the forked child
+    unsafe {
+        process.pre_exec(move || {
+            // the calling process always exists, so -1 is a real niceness here
+            let current = libc::getpriority(libc::PRIO_PROCESS, 0);
+            if libc::setpriority(libc::PRIO_PROCESS, 0, current + adjustment) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn niced(_process: &mut Command, _adjustment: i32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "nice is only supported on Unix"))
+}
+
+/// Describes a path as tab-separated `TYPE SIZE MODE MTIME` fields, with the
+/// mode in octal and the modification time in seconds since the Unix epoch.
+fn file_info(path: &Path) -> io::Result<String> {
+    let meta = fs::metadata(path)?;
+    let kind = if meta.is_dir() {
+        "directory"
+    } else if meta.is_file() {
+        "file"
+    } else {
+        "other"
+    };
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        format!("{:04o}", meta.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = if meta.permissions().readonly() { "ro" } else { "rw" };
+    let mtime = meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(|| "-".to_string(), |since| since.as_secs().to_string());
+    Ok(format!("{}\t{}\t{}\t{}", kind, meta.len(), mode, mtime))
+}
+
+/// The directory the user believes they are in: `$PWD` when it still names
+/// the current directory, otherwise the physical current directory. If the
+/// current directory has been removed, `$PWD` is the only record of it.
+fn logical_cwd() -> io::Result<PathBuf> {
+    let pwd = env::var_os("PWD").map(PathBuf::from).filter(|pwd| pwd.is_absolute());
+    match (env::current_dir(), pwd) {
+        (Ok(physical), Some(pwd)) if pwd.canonicalize().ok().as_ref() == Some(&physical) => Ok(pwd),
+        (Ok(physical), _) => Ok(physical),
+        (Err(_), Some(pwd)) => Ok(pwd),
+        (Err(reason), None) => Err(stale_cwd(reason)),
+    }
+}
+
+/// Explains a failed relative `cd` from a directory that no longer exists.
+fn stale_cwd(reason: io::Error) -> io::Error {
+    io::Error::new(reason.kind(), "the current directory no longer exists; cd to an absolute path")
+}
+
+/// Lexically resolves `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+/// Changes the current directory and keeps `$PWD` in sync, leaving the one
+/// it came from in `$OLDPWD` for `cd -`. Logical mode resolves `..` against
+/// `$PWD` as written, physical mode follows symlinks.
+fn change_dir(path: &Path, mode: CdMode) -> io::Result<()> {
+    let previous = logical_cwd();
+    let explain = |reason: io::Error| match env::current_dir() {
+        Err(_) if path.is_relative() => stale_cwd(reason),
+        _ => reason,
+    };
+    let pwd = match mode {
+        CdMode::Logical => {
+            let target = if path.is_absolute() {
+                normalize(path)
+            } else {
+                normalize(&logical_cwd()?.join(path))
+            };
+            env::set_current_dir(&target).map_err(explain)?;
+            target
+        }
+        CdMode::Physical => {
+            env::set_current_dir(path).map_err(explain)?;
+            env::current_dir()?
+        }
+    };
+    if let Ok(previous) = previous {
+        env::set_var("OLDPWD", previous);
+    }
+    env::set_var("PWD", pwd);
+    Ok(())
+}
+
+/// Reads a command into `buffer`, prompting for and adding each line it
+/// continues onto. Returns the length of its first line, which is 0 at the
+/// end of input.
+fn read_command(stdin: &mut impl BufRead, stdout: &mut impl Write, buffer: &mut String) -> io::Result<usize> {
+    let n = read_line(stdin, buffer)?;
+    if n == 0 {
+        return Ok(0);
+    }
+    while let Some(len) = lexer::strip_continuation(buffer.trim_end_matches(['\n', '\r'])).map(str::len) {
+        buffer.truncate(len);
+        write!(stdout, "{}", "> ".bold())?;
+        stdout.flush()?;
+        if read_line(stdin, buffer)? == 0 {
+            break;
+        }
+    }
+    Ok(n)
+}
+
+/// Appends a line to `buffer` like `BufRead::read_line`, which retries a
+/// read that a signal interrupted. This gives up with the interruption
+/// instead when it came from Ctrl-C, dropping what was read of the line.
+fn read_line(stdin: &mut impl BufRead, buffer: &mut String) -> io::Result<usize> {
+    let mut line = Vec::new();
+    loop {
+        let available = match stdin.fill_buf() {
+            Err(reason) if reason.kind() == io::ErrorKind::Interrupted && !signal::interrupted() => continue,
+            available => available?,
+        };
+        let (used, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (available.len(), available.is_empty()),
+        };
+        line.extend_from_slice(&available[..used]);
+        stdin.consume(used);
+        if done {
+            break;
+        }
+    }
+    let line = String::from_utf8(line)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8"))?;
+    buffer.push_str(&line);
+    Ok(line.len())
+}
+
+/// How many consecutive EOFs on an empty line are ignored before exiting,
+/// following bash: unset means none, a non-numeric value means 10.
+fn ignored_eofs() -> u32 {
+    match env::var("IGNOREEOF") {
+        Ok(count) => count.parse().unwrap_or(10),
+        Err(_) => 0,
+    }
+}
+
+/// Command-line switches that change how each line is evaluated.
+#[derive(Default, Clone)]
+struct Flags {
+    /// Print each parsed `Expr` to stderr before running it.
+    debug_ast: bool,
+    /// Print the tokens of each line to stderr instead of running it.
+    emit_tokens: bool,
+    /// Read commands from stdin even when it is a terminal.
+    stdin_commands: bool,
+    /// Don't suggest similar names for unknown commands in interactive mode.
+    no_suggest: bool,
+}
+
+/// The syntax-error status, as in other shells.
+const SYNTAX_ERROR: i32 = 2;
+
+/// Runs commands read from `input`, normally the terminal, prompting for
+/// each on `out`. Returns the last command's status for the shell to exit
+/// with.
+fn interactive(shell: &mut Shell, input: &mut impl BufRead, out: &mut impl Output) -> io::Result<i32> {
     let mut buffer = String::new();
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
+    let mut eofs = 0;
 
     loop {
-        write!(stdout, "{}", "chitin> ".bold())?;
-        stdout.flush()?;
+        let prompt = match env::var("CHITIN_PS1") {
+            Ok(template) => {
+                let dir = logical_cwd().map_or_else(|_| "?".to_string(), |dir| home_relative(&dir, env::var_os("HOME").as_deref()));
+                render_prompt(&template, &dir, is_root())
+            }
+            Err(_) => "chitin> ".to_string(),
+        };
+        write!(out, "{}", prompt.bold())?;
+        out.flush()?;
 
         buffer.clear();
-        let n = stdin.read_line(&mut buffer)?;
-        let input = buffer[..n].trim();
+        // only a Ctrl-C while waiting here cuts the read short
+        signal::catch_interrupts(true);
+        let read = read_command(input, out, &mut buffer);
+        signal::catch_interrupts(false);
+        let n = match read {
+            Err(reason) if reason.kind() == io::ErrorKind::Interrupted => {
+                // the terminal echoed ^C; drop the line and prompt again
+                writeln!(out)?;
+                signal::clear_interrupt();
+                shell.status = signal::INTERRUPT_STATUS;
+                eofs = 0;
+                continue;
+            }
+            read => read?,
+        };
+        if n == 0 {
+            writeln!(out)?;
+            eofs += 1;
+            if eofs > ignored_eofs() {
+                break;
+            }
+            writeln!(out, "Use \"exit\" to leave the shell.")?;
+            continue;
+        }
+        eofs = 0;
+        let input = buffer.trim();
+        if shell.flags.emit_tokens {
+            eprintln!("{}", token_dump(input));
+            continue;
+        }
 
-        match Expr::try_from(Lexer::new(input)) {
-            Ok(comb) => match comb.run(&mut stdout) {
-                Ok(ResultKind::Ok) => (),
-                Err(reason) => {
-                    write!(stdout, "{}\n", reason.to_string().bright_red())?;
-                }
-                Ok(ResultKind::Exit) => break,
+        // run whatever parsed cleanly before reporting a syntax error
+        let (expr, error) = Expr::parse_leading(Lexer::new(input));
+        // forget a Ctrl-C pressed at the prompt
+        signal::clear_interrupt();
+        match shell.eval(expr, out) {
+            Ok(ResultKind::Status(_)) => {}
+            Err(reason) => shell.report_error(&reason)?,
+            Ok(ResultKind::Exit(_)) => break,
+        }
+        if signal::interrupted() {
+            // the terminal echoed ^C; start the next prompt on a fresh line
+            writeln!(out)?;
+        }
+        if let Some(reason) = error {
+            shell.status = SYNTAX_ERROR;
+            shell.report(reason)?;
+        }
+    }
+    Ok(shell.status)
+}
+
+/// The prompt `template` describes, read from `$CHITIN_PS1` as each prompt is
+/// shown: `\w` is `dir`, `\$` is `#` for root and `$` for everyone else, as
+/// in bash, and `\\` a backslash. Any other backslash is kept as is.
+fn render_prompt(template: &str, dir: &str, root: bool) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.clone().next() {
+            Some('w') => prompt.push_str(dir),
+            Some('$') => prompt.push(if root { '#' } else { '$' }),
+            Some('\\') => prompt.push('\\'),
+            _ => {
+                prompt.push(c);
+                continue;
             }
-            Err(reason) => write!(stdout, "{}\n", reason.to_string().bright_red())?,
         }
+        chars.next();
+    }
+    prompt
+}
+
+/// `dir` for display, shortened to start with `~` when it is inside `home`.
+fn home_relative(dir: &Path, home: Option<&OsStr>) -> String {
+    if let Some(home) = home.filter(|home| !home.is_empty()) {
+        match dir.strip_prefix(home) {
+            Ok(rest) if rest.as_os_str().is_empty() => return "~".to_string(),
+            Ok(rest) => return Path::new("~").join(rest).display().to_string(),
+            Err(_) => (),
+        }
+    }
+    dir.display().to_string()
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Prints an error, in red if `styled`. The message is always passed as an
+/// argument, never as a format string, so user-supplied text like `{}` or
+/// `%s` prints as is.
+fn report_error(out: &mut impl Write, reason: impl fmt::Display, styled: bool) -> io::Result<()> {
+    if styled {
+        writeln!(out, "{}", reason.to_string().bright_red())
+    } else {
+        writeln!(out, "{}", reason)
+    }
+}
+
+/// Prints a "did you mean" hint when `reason` is an unknown command with a
+/// close match among the builtins and the programs on `PATH`.
+fn suggest_command(out: &mut impl Write, reason: &io::Error) -> io::Result<()> {
+    let Some(CommandNotFound(name)) = reason.get_ref().and_then(|inner| inner.downcast_ref()) else {
+        return Ok(());
+    };
+    let commands = suggest::commands();
+    if let Some(hint) = suggest::closest(name, commands.iter().map(String::as_str)) {
+        writeln!(out, "did you mean '{}'?", hint)?;
     }
     Ok(())
 }
 
-fn batch(src: &String) -> io::Result<()> {
-    let file = File::open(src)?;
-    let reader = BufReader::new(file);
-    let mut stdout = io::stdout();
-    for line in reader.lines() {
-        let input = line?;
+/// What `--debug-ast` prints for a parsed line.
+fn ast_dump(expr: &Expr) -> String {
+    format!("{:?}", expr)
+}
+
+/// What `--emit-tokens` prints for a line instead of running it.
+fn token_dump(input: &str) -> String {
+    format!("{:?}", Lexer::new(input).collect::<Vec<_>>())
+}
+
+/// Shows a line of script input before it runs, on a line of its own so the
+/// command's output starts below it.
+fn echo_input(out: &mut impl Write, input: &str) -> io::Result<()> {
+    writeln!(out, "{}", input.bold())
+}
+
+/// Runs each line of `reader` without prompting, returning the last
+/// command's status, or `ResultKind::Exit` with it if a command exited
+/// before the end. Script files echo every line before running it;
+/// commands piped on stdin do not. Errors are prefixed with `source` and the
+/// line they came from, so a failing script points at the line to look at.
+/// A line ending in a backslash continues on the next one, and errors in the
+/// joined command name the line it started on.
+fn batch(reader: impl BufRead, out: &mut impl Output, source: &str, echo: bool, shell: &mut Shell) -> CommandResult {
+    let mut exited = false;
+    let mut lines = (1..).zip(reader.lines());
+    shell.source = Some(source.to_string());
+    while let Some((number, line)) = lines.next() {
+        shell.line = number;
+        let mut input = line?;
+        while let Some(len) = lexer::strip_continuation(&input).map(str::len) {
+            input.truncate(len);
+            match lines.next() {
+                Some((_, line)) => input.push_str(&line?),
+                None => break,
+            }
+        }
         if input.is_empty() {
             continue;
         }
-        write!(stdout, "{}", input.bold())?;
+        if echo {
+            echo_input(out, &input)?;
+        }
+        if shell.flags.emit_tokens {
+            eprintln!("{}", token_dump(&input));
+            continue;
+        }
 
-        match Expr::try_from(Lexer::new(input.as_str())) {
-            Ok(comb) => match comb.run(&mut stdout) {
-                Ok(ResultKind::Ok) => (),
-                Err(reason) => {
-                    write!(stdout, "{}\n", reason.to_string().bright_red().red())?;
-                }
-                Ok(ResultKind::Exit) => break,
+        let comb = match Expr::try_from(Lexer::new(input.as_str())) {
+            Ok(comb) => comb,
+            Err(reason) => {
+                shell.status = SYNTAX_ERROR;
+                shell.report(reason)?;
+                continue;
+            }
+        };
+        match shell.eval(comb, out) {
+            Ok(ResultKind::Status(_)) => {}
+            Err(reason) => shell.report_error(&reason)?,
+            Ok(ResultKind::Exit(_)) => {
+                exited = true;
+                break;
             }
-            Err(reason) => write!(stdout, "{}\n", reason.to_string().bright_red())?,
         }
     }
-    Ok(())
+    shell.source = None;
+    Ok(if exited { ResultKind::Exit(Some(shell.status)) } else { ResultKind::Status(shell.status) })
+}
+
+/// The version, target and optional capabilities this binary was built with.
+fn build_info() -> String {
+    let features = [("color", true), ("nice", cfg!(unix))]
+        .iter()
+        .map(|&(name, enabled)| format!("{}{}", if enabled { '+' } else { '-' }, name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "chitin {} ({}-{})\nfeatures: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::ARCH,
+        env::consts::OS,
+        features,
+    )
+}
+
+/// Runs the commands in `~/.chitinrc`, or in `rcfile` if one was given.
+/// A missing default rc file is fine; a missing `rcfile` is reported.
+/// Returns `ResultKind::Exit` if the rc file exited.
+fn source_rcfile(rcfile: Option<String>, shell: &mut Shell) -> CommandResult {
+    let (path, file) = match rcfile {
+        Some(path) => {
+            let file = File::open(&path);
+            if let Err(reason) = &file {
+                shell.report(format_args!("{}: {}", path, reason))?;
+            }
+            (path, file)
+        }
+        None => {
+            let Some(home) = env::var_os("HOME") else {
+                return Ok(ResultKind::SUCCESS);
+            };
+            let path = Path::new(&home).join(".chitinrc");
+            let file = File::open(&path);
+            (path.display().to_string(), file)
+        }
+    };
+    match file {
+        Ok(file) => batch(BufReader::new(file), &mut io::stdout(), &path, false, shell),
+        Err(_) => Ok(ResultKind::SUCCESS),
+    }
+}
+
+/// Whether output to a stream should be styled, given whether it is a
+/// terminal and the values of `NO_COLOR` and `TERM`: not when `NO_COLOR` is
+/// set, when the stream isn't a terminal, or when `TERM` is unset or `dumb`,
+/// so logs and pipes never fill up with escape codes.
+fn should_colorize(is_terminal: bool, no_color: Option<&OsStr>, term: Option<&OsStr>) -> bool {
+    no_color.is_none_or(|no_color| no_color.is_empty())
+        && is_terminal
+        && term.is_some_and(|term| !term.is_empty() && term != "dumb")
+}
+
+/// Where the shell reads its commands from.
+#[derive(Debug, PartialEq)]
+enum Mode {
+    /// The script named on the command line.
+    Script(String),
+    /// Stdin, without a prompt.
+    Piped,
+    /// The terminal, with a prompt.
+    Interactive,
+}
+
+impl Mode {
+    /// A named script is run, and otherwise stdin is read without a prompt
+    /// when it isn't a terminal or `--stdin-commands` was given.
+    fn new(script: Option<String>, stdin_commands: bool, stdin_is_terminal: bool) -> Self {
+        match script {
+            Some(script) => Self::Script(script),
+            None if stdin_commands || !stdin_is_terminal => Self::Piped,
+            None => Self::Interactive,
+        }
+    }
+
+    /// Whether the rc file is read first. Only interactive shells read it,
+    /// so scripts behave the same for everyone.
+    fn sources_rcfile(&self, norc: bool) -> bool {
+        *self == Self::Interactive && !norc
+    }
+}
+
+fn usage_error(reason: &str) -> ! {
+    eprintln!("chitin: {}", reason);
+    process::exit(2)
 }
 
 fn main() -> io::Result<()> {
-    let args: Vec<_> = env::args().collect();
-    match args.len() {
-        1 => interactive(),
-        2 => batch(&args[1]),
-        _ => panic!("too many arguments")
+    // every styled string goes through `colored`, so this covers them all;
+    // errors are only styled if stderr is a terminal too
+    let (no_color, term) = (env::var_os("NO_COLOR"), env::var_os("TERM"));
+    colored::control::set_override(should_colorize(io::stdout().is_terminal(), no_color.as_deref(), term.as_deref()));
+    let mut flags = Flags::default();
+    let mut script = None;
+    let mut rcfile = None;
+    let mut norc = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--build-info" => {
+                print!("{}", build_info());
+                return Ok(());
+            }
+            "--debug-ast" => flags.debug_ast = true,
+            "--emit-tokens" => flags.emit_tokens = true,
+            "--stdin-commands" => flags.stdin_commands = true,
+            "--no-suggest" => flags.no_suggest = true,
+            "--rcfile" => rcfile = Some(args.next().unwrap_or_else(|| usage_error("option '--rcfile' needs a file"))),
+            "--norc" => norc = true,
+            opt if opt.starts_with("--") => usage_error(&format!("unknown option '{}'", opt)),
+            _ if script.is_some() => usage_error("too many arguments"),
+            _ => script = Some(arg),
+        }
+    }
+    let mode = Mode::new(script, flags.stdin_commands, io::stdin().is_terminal());
+    let mut shell = Shell::new(flags);
+    let status = match &mode {
+        Mode::Script(src) => {
+            batch(BufReader::new(File::open(src)?), &mut io::stdout(), src, true, &mut shell).map(|_| shell.status)
+        }
+        Mode::Piped => batch(io::stdin().lock(), &mut io::stdout(), "chitin", false, &mut shell).map(|_| shell.status),
+        Mode::Interactive => {
+            let sourced = if mode.sources_rcfile(norc) {
+                source_rcfile(rcfile, &mut shell)?
+            } else {
+                ResultKind::SUCCESS
+            };
+            match sourced {
+                // as in bash, an rc file that exits ends the shell before its first prompt
+                ResultKind::Exit(_) => Ok(shell.status),
+                ResultKind::Status(_) => interactive(&mut shell, &mut io::stdin().lock(), &mut io::stdout()),
+            }
+        }
+    }?;
+    process::exit(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, sync::{Mutex, MutexGuard}};
+
+    use super::*;
+
+    /// Held by every test that runs commands, since they share the process's
+    /// environment and current directory.
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn shell() -> Shell {
+        Shell::new(Flags { no_suggest: true, ..Flags::default() })
+    }
+
+    /// Makes `shell` report errors, uncoloured, into the buffer returned.
+    fn capture_errors(shell: &mut Shell) -> Rc<RefCell<Vec<u8>>> {
+        colored::control::set_override(false);
+        let errors = Rc::default();
+        shell.errors = Errors::Buffer(Rc::clone(&errors));
+        errors
+    }
+
+    fn errors(captured: &Rc<RefCell<Vec<u8>>>) -> String {
+        String::from_utf8(captured.take()).unwrap()
+    }
+
+    /// Runs `line` in `shell`, returning what it printed and its status.
+    fn eval(shell: &mut Shell, line: &str) -> (String, i32) {
+        let mut out = Vec::new();
+        let _ = shell.eval(Expr::try_from(Lexer::new(line)).expect("line parses"), &mut out);
+        (String::from_utf8(out).expect("output is UTF-8"), shell.status)
+    }
+
+    /// A fresh directory, with its real path so `$PWD` can be compared to it.
+    fn scratch() -> PathBuf {
+        temp::dir(&env::temp_dir().join("chitin-test.XXXXXX")).unwrap().canonicalize().unwrap()
+    }
+
+    /// Puts back the current directory and the given variables when
+    /// dropped, so a test can change them freely.
+    struct Restore {
+        dir: PathBuf,
+        vars: Vec<(&'static str, Option<OsString>)>,
+    }
+
+    impl Restore {
+        fn new(names: &[&'static str]) -> Self {
+            let vars = ["PWD", "OLDPWD"].iter().chain(names).map(|&name| (name, env::var_os(name))).collect();
+            Self { dir: env::current_dir().unwrap(), vars }
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            env::set_current_dir(&self.dir).unwrap();
+            for (name, value) in &self.vars {
+                match value {
+                    Some(value) => env::set_var(name, value),
+                    None => env::remove_var(name),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn and_or_follows_the_status() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "true && echo yes || echo no"), ("yes\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "false && echo yes || echo no"), ("no\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "false || false"), (String::new(), 1));
+    }
+
+    #[test]
+    fn a_command_that_cannot_run_fails_without_printing_into_the_output() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "nonexist_zz || echo fb"), ("fb\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "nonexist_zz && echo no"), (String::new(), 127));
+        assert_eq!(eval(&mut shell, "echo \"[$(nonexist_zz || echo fb)]\""), ("[fb]\n".to_string(), 0));
+    }
+
+    #[test]
+    fn command_substitution_captures_output() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "echo $(echo hi)"), ("hi\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo $(echo $(echo deep) er)"), ("deep er\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "printf '<%s>' $(printf ' a  b\\n\\n') \"$(echo '  c ')\""), ("<a><b><  c >".to_string(), 0));
+    }
+
+    #[test]
+    fn command_substitution_changes_nothing_outside_it() {
+        let _lock = lock();
+        let mut shell = shell();
+        let dir = env::current_dir().unwrap();
+        eval(&mut shell, "echo $(cd /; pwd)");
+        assert_eq!(env::current_dir().unwrap(), dir);
+        assert_eq!(eval(&mut shell, "x=$(CHITIN_TEST_SUB=1); echo \"[$CHITIN_TEST_SUB]\""), ("[]\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo $(alias zz=echo) $(exit 3) $?").0, "0\n");
+        assert!(!shell.aliases.contains_key("zz"));
+    }
+
+    #[test]
+    fn prompts_render_their_escapes() {
+        assert_eq!(render_prompt("[\\w]\\$ ", "~/src", false), "[~/src]$ ");
+        assert_eq!(render_prompt("\\$ ", "/", true), "# ");
+        assert_eq!(render_prompt("a\\\\b \\x", "/", false), "a\\b \\x");
+        assert_eq!(render_prompt("end\\", "/", false), "end\\");
+        assert_eq!(render_prompt("plain> ", "/", false), "plain> ");
+    }
+
+    #[test]
+    fn the_prompt_directory_abbreviates_home() {
+        let home = Some(OsStr::new("/home/me"));
+        assert_eq!(home_relative(Path::new("/home/me"), home), "~");
+        assert_eq!(home_relative(Path::new("/home/me/src"), home), "~/src");
+        assert_eq!(home_relative(Path::new("/home/meat"), home), "/home/meat");
+        assert_eq!(home_relative(Path::new("/tmp"), None), "/tmp");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cd_is_logical_unless_told_otherwise() {
+        let _lock = lock();
+        let _restore = Restore::new(&[]);
+        let dir = scratch();
+        fs::create_dir_all(dir.join("a/real")).unwrap();
+        fs::create_dir(dir.join("-dir")).unwrap();
+        std::os::unix::fs::symlink(dir.join("a/real"), dir.join("link")).unwrap();
+        let mut shell = shell();
+        let pwd = |shell: &mut Shell, cd: &str| eval(shell, &format!("{}; echo $PWD", cd));
+        let shown = dir.display();
+        assert_eq!(pwd(&mut shell, &format!("cd {}/link", shown)), (format!("{}/link\n", shown), 0));
+        assert_eq!(pwd(&mut shell, "cd -L .."), (format!("{}\n", shown), 0));
+        assert_eq!(pwd(&mut shell, &format!("cd -P {}/link", shown)), (format!("{}/a/real\n", shown), 0));
+        assert_eq!(pwd(&mut shell, &format!("cd {}/link/..", shown)), (format!("{}\n", shown), 0));
+        assert_eq!(pwd(&mut shell, &format!("cd -P {}/link/..", shown)), (format!("{}/a\n", shown), 0));
+        assert_eq!(pwd(&mut shell, &format!("cd {}; cd -- -dir", shown)), (format!("{}/-dir\n", shown), 0));
+        assert_eq!(eval(&mut shell, "cd -x").1, 1);
+        // pwd shows the path taken, not where the link leads
+        assert_eq!(eval(&mut shell, &format!("cd {}/link; pwd", shown)), (format!("{}/link\n", shown), 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cd_dash_goes_back_to_the_previous_directory() {
+        let _lock = lock();
+        let _restore = Restore::new(&[]);
+        let dir = scratch();
+        fs::create_dir(dir.join("a")).unwrap();
+        fs::create_dir(dir.join("b")).unwrap();
+        let mut shell = shell();
+        let captured = capture_errors(&mut shell);
+        env::remove_var("OLDPWD");
+        assert_eq!(eval(&mut shell, "cd -").1, 1);
+        assert_eq!(errors(&captured), "cd: OLDPWD not set\n");
+        let (a, b) = (dir.join("a").display().to_string(), dir.join("b").display().to_string());
+        let line = format!("cd {}; cd {}; cd -; echo $PWD $OLDPWD", a, b);
+        assert_eq!(eval(&mut shell, &line), (format!("{0}\n{0} {1}\n", a, b), 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cd_still_works_after_the_current_directory_is_removed() {
+        let _lock = lock();
+        let _restore = Restore::new(&[]);
+        let dir = scratch();
+        fs::create_dir(dir.join("gone")).unwrap();
+        let mut shell = shell();
+        let captured = capture_errors(&mut shell);
+        eval(&mut shell, &format!("cd {}/gone", dir.display()));
+        fs::remove_dir(dir.join("gone")).unwrap();
+        assert_eq!(eval(&mut shell, "pwd"), (format!("{}/gone\n", dir.display()), 0));
+        assert_eq!(eval(&mut shell, "cd sub").1, 1);
+        assert_eq!(errors(&captured), "the current directory no longer exists; cd to an absolute path\n");
+        assert_eq!(eval(&mut shell, &format!("cd {}; pwd", dir.display())), (format!("{}\n", dir.display()), 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn nice_raises_the_niceness_of_the_command() {
+        let _lock = lock();
+        // SAFETY: getpriority only reads this process's niceness
+        let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        let mut shell = shell();
+        let (shown, status) = eval(&mut shell, "nice -n 5 sh -c 'ps -o ni= -p $$'");
+        assert_eq!((shown.trim().parse::<i32>().unwrap(), status), ((current + 5).min(19), 0));
+        let captured = capture_errors(&mut shell);
+        assert_eq!(eval(&mut shell, "nice -n 40 true").1, 1);
+        assert_eq!(errors(&captured), "adjustment supplied to 'nice' is out of range\n");
+        assert_eq!(eval(&mut shell, "nice -n 5").1, 1);
+        assert_eq!(errors(&captured), "wrong number of arguments supplied to 'nice'\n");
+    }
+
+    #[test]
+    fn mktemp_makes_a_new_file_or_directory_each_time() {
+        let _lock = lock();
+        let mut shell = shell();
+        let (made, status) = eval(&mut shell, "mktemp; mktemp; mktemp -d");
+        let paths: Vec<_> = made.lines().map(PathBuf::from).collect();
+        assert_eq!((paths.len(), status), (3, 0));
+        assert_ne!(paths[0], paths[1]);
+        assert!(paths[0].is_file() && paths[1].is_file() && paths[2].is_dir());
+        let dir = scratch();
+        let (made, status) = eval(&mut shell, &format!("mktemp {}/fileXXX", dir.display()));
+        assert!(made.starts_with(&format!("{}/file", dir.display())) && status == 0, "{}", made);
+        let captured = capture_errors(&mut shell);
+        assert_eq!(eval(&mut shell, &format!("mktemp {}/fileXX", dir.display())), (String::new(), 1));
+        assert_eq!(errors(&captured), "too few X's in template\n");
+        fs::remove_file(&paths[0]).unwrap();
+        fs::remove_file(&paths[1]).unwrap();
+        fs::remove_dir(&paths[2]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn error_messages_are_printed_verbatim() {
+        let _lock = lock();
+        colored::control::set_override(false);
+        let mut out = Vec::new();
+        report_error(&mut out, "{}", false).unwrap();
+        report_error(&mut out, "%s: {0} %d", false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{}\n%s: {0} %d\n");
+    }
+
+    #[test]
+    fn the_token_dump_shows_arguments_and_operators_in_order() {
+        assert_eq!(
+            token_dump("echo a|wc -l >> f"),
+            concat!(
+                r#"[Arg(Word([Unquoted("echo")])), Arg(Word([Unquoted("a")])), Op("|"), "#,
+                r#"Arg(Word([Unquoted("wc")])), Arg(Word([Unquoted("-l")])), Op(">>"), Arg(Word([Unquoted("f")]))]"#,
+            )
+        );
+    }
+
+    #[test]
+    fn the_ast_dump_shows_redirects_and_sequences() {
+        let expr = Expr::try_from(Lexer::new("echo hi > out; ls")).unwrap();
+        assert_eq!(
+            ast_dump(&expr),
+            concat!(
+                r#"Expr([AndOr(Combinator { stages: [Stage { cmd: Simple { assignments: [], "#,
+                r#"words: [Word([Unquoted("echo")]), Word([Unquoted("hi")])] }, input: None, errors: None }], "#,
+                r#"output: Some((Truncate, Word([Unquoted("out")]))) }, []), "#,
+                r#"AndOr(Combinator { stages: [Stage { cmd: Simple { assignments: [], "#,
+                r#"words: [Word([Unquoted("ls")])] }, input: None, errors: None }], output: None }, [])])"#,
+            )
+        );
+    }
+
+    #[test]
+    fn colour_is_only_used_on_a_capable_terminal() {
+        let term = Some(OsStr::new("xterm-256color"));
+        assert!(should_colorize(true, None, term));
+        assert!(!should_colorize(false, None, term));
+        // NO_COLOR counts only when it is set to something
+        assert!(!should_colorize(true, Some(OsStr::new("1")), term));
+        assert!(should_colorize(true, Some(OsStr::new("")), term));
+        assert!(!should_colorize(true, None, Some(OsStr::new("dumb"))));
+        assert!(!should_colorize(true, None, Some(OsStr::new(""))));
+        assert!(!should_colorize(true, None, None));
+    }
+
+    #[test]
+    fn only_an_interactive_shell_reads_the_rc_file() {
+        let script = Mode::new(Some("f".to_string()), false, true);
+        assert_eq!(script, Mode::Script("f".to_string()));
+        assert_eq!(Mode::new(None, false, false), Mode::Piped);
+        assert_eq!(Mode::new(None, true, true), Mode::Piped);
+        assert_eq!(Mode::new(None, false, true), Mode::Interactive);
+        assert!(Mode::Interactive.sources_rcfile(false));
+        assert!(!Mode::Interactive.sources_rcfile(true));
+        assert!(!script.sources_rcfile(false));
+        assert!(!Mode::Piped.sources_rcfile(false));
+    }
+
+    #[test]
+    fn build_info_names_the_version_and_features() {
+        let info = build_info();
+        assert!(info.starts_with(&format!("chitin {} (", env!("CARGO_PKG_VERSION"))), "{}", info);
+        let features = info.lines().find_map(|line| line.strip_prefix("features: ")).unwrap();
+        assert!(features.split(' ').any(|feature| feature == "+color"), "{}", info);
+        assert!(info.ends_with('\n'));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn names_that_are_not_utf8_can_be_redirected_to_and_globbed() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let _lock = lock();
+        let _restore = Restore::new(&["NAME"]);
+        let dir = scratch();
+        let name = OsString::from_vec(b"a\xff".to_vec());
+        env::set_var("NAME", &name);
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, &format!("cd {}; echo hi > $NAME; echo more >> $NAME", dir.display())).1, 0);
+        assert_eq!(fs::read(dir.join(&name)).unwrap(), b"hi\nmore\n");
+        let mut out = Vec::new();
+        shell.eval(Expr::try_from(Lexer::new("echo a*; cat a?")).unwrap(), &mut out).unwrap();
+        assert_eq!(out, [name.as_bytes(), b"\nhi\nmore\n"].concat());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn finfo_describes_each_path() {
+        use std::os::unix::fs::PermissionsExt;
+        let _lock = lock();
+        let dir = scratch();
+        fs::write(dir.join("f"), "abc").unwrap();
+        fs::set_permissions(dir.join("f"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        let mut shell = shell();
+        let (file, status) = eval(&mut shell, &format!("finfo {}/f", dir.display()));
+        assert!(file.starts_with(&format!("{}/f\tfile\t3\t0640\t", dir.display())), "{}", file);
+        assert_eq!((file.matches('\t').count(), status), (4, 0));
+        let (directory, status) = eval(&mut shell, &format!("finfo {}/d", dir.display()));
+        assert!(directory.starts_with(&format!("{}/d\tdirectory\t", dir.display())), "{}", directory);
+        assert_eq!(status, 0);
+        // the paths that exist are still described
+        let (both, status) = eval(&mut shell, &format!("finfo {0}/missing {0}/f", dir.display()));
+        assert_eq!((both, status), (file, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cdf_changes_into_the_directory_of_a_file() {
+        let _lock = lock();
+        let _restore = Restore::new(&[]);
+        let dir = scratch();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/file.txt"), "").unwrap();
+        let mut shell = shell();
+        let shown = dir.display();
+        assert_eq!(eval(&mut shell, &format!("cdf {}/a/b/file.txt; echo $PWD", shown)), (format!("{}/a/b\n", shown), 0));
+        assert_eq!(eval(&mut shell, &format!("cdf {}/a; echo $PWD", shown)), (format!("{}/a\n", shown), 0));
+        assert_eq!(eval(&mut shell, "cdf b/file.txt; echo $PWD"), (format!("{}/a/b\n", shown), 0));
+        assert_eq!(eval(&mut shell, "cdf file.txt; echo $PWD"), (format!("{}/a/b\n", shown), 0));
+        assert_eq!(eval(&mut shell, "cdf missing.txt").1, 1);
+        assert_eq!(env::current_dir().unwrap(), dir.join("a/b"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Captured output that claims to be a terminal.
+    struct Tty(Vec<u8>);
+
+    impl Write for Tty {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output for Tty {
+        fn is_tty(&self) -> bool {
+            true
+        }
+
+        fn stdio(&mut self) -> io::Result<Option<Stdio>> {
+            Ok(None)
+        }
+    }
+
+    /// A terminal that programs are handed directly, so nothing they print
+    /// should pass through the shell.
+    struct Inherited(File);
+
+    impl Write for Inherited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            panic!("{:?} was captured instead of inherited", String::from_utf8_lossy(buf));
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output for Inherited {
+        fn is_tty(&self) -> bool {
+            true
+        }
+
+        fn stdio(&mut self) -> io::Result<Option<Stdio>> {
+            self.0.stdio()
+        }
+    }
+
+    #[test]
+    fn programs_inherit_an_output_that_offers_itself() {
+        let _lock = lock();
+        assert!(io::stdout().stdio().unwrap().is_some());
+        assert!(Vec::new().stdio().unwrap().is_none());
+        let dir = scratch();
+        let mut terminal = Inherited(File::create(dir.join("terminal")).unwrap());
+        let mut shell = shell();
+        let res = shell.eval(Expr::try_from(Lexer::new("sh -c 'echo direct'")).unwrap(), &mut terminal);
+        assert!(matches!(res, Ok(ResultKind::Status(0))));
+        assert_eq!(fs::read_to_string(dir.join("terminal")).unwrap(), "direct\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clear_only_writes_to_a_terminal() {
+        let _lock = lock();
+        let mut shell = shell();
+        let mut tty = Tty(Vec::new());
+        shell.eval(Expr::try_from(Lexer::new("clear")).unwrap(), &mut tty).unwrap();
+        assert_eq!(tty.0, b"\x1b[2J\x1b[H");
+        assert_eq!(eval(&mut shell, "clear"), (String::new(), 0));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn an_interrupt_abandons_the_rest_of_the_line() {
+        let _lock = lock();
+        signal::catch_interrupts(false);
+        let mut shell = shell();
+        // the child interrupts the shell, as Ctrl-C would
+        let line = "sh -c 'kill -INT $PPID'; echo not reached";
+        let mut out = Vec::new();
+        let res = shell.eval(Expr::try_from(Lexer::new(line)).unwrap(), &mut out);
+        signal::clear_interrupt();
+        assert!(matches!(res, Ok(ResultKind::Status(signal::INTERRUPT_STATUS))));
+        assert_eq!((out, shell.status), (Vec::new(), signal::INTERRUPT_STATUS));
+    }
+
+    #[test]
+    fn a_leading_tilde_is_home_for_every_command() {
+        let _lock = lock();
+        let _restore = Restore::new(&["HOME"]);
+        let home = scratch();
+        fs::create_dir(home.join("sub")).unwrap();
+        env::set_var("HOME", &home);
+        let mut shell = shell();
+        let shown = home.display();
+        assert_eq!(eval(&mut shell, "cd ~/sub; echo $PWD"), (format!("{}/sub\n", shown), 0));
+        assert_eq!(eval(&mut shell, "echo ~ \"~\" '~'/x a~"), (format!("{} ~ ~/x a~\n", shown), 0));
+        eval(&mut shell, "echo hi > ~/f");
+        assert_eq!(fs::read_to_string(home.join("f")).unwrap(), "hi\n");
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    /// The names of the entries in `dir`, sorted.
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> =
+            fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn insert_redirects_prepend_and_clean_up() {
+        use std::os::unix::fs::PermissionsExt;
+        let _lock = lock();
+        let dir = scratch();
+        let file = dir.join("f");
+        fs::write(&file, "orig\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let mut shell = shell();
+        let shown = file.display();
+        assert_eq!(eval(&mut shell, &format!("echo new >+ {}", shown)), (String::new(), 0));
+        assert_eq!(eval(&mut shell, &format!("printf 'x\\n' | cat >+ {}", shown)), (String::new(), 0));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "x\nnew\norig\n");
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(entries(&dir), ["f"]);
+        // a command that cannot run leaves neither a change nor a temp file
+        assert_eq!(eval(&mut shell, &format!("nonexist_zz >+ {}", shown)).1, 127);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "x\nnew\norig\n");
+        assert_eq!(entries(&dir), ["f"]);
+        assert_eq!(eval(&mut shell, &format!("echo a >+ {}/missing", dir.display())).1, 1);
+        assert_eq!(entries(&dir), ["f"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pipes_and_redirects_combine() {
+        let _lock = lock();
+        let dir = scratch();
+        let (input, output) = (dir.join("in").display().to_string(), dir.join("out").display().to_string());
+        let mut shell = shell();
+        eval(&mut shell, &format!("printf 'b\\na\\n' > {}", input));
+        assert_eq!(eval(&mut shell, &format!("sort < {} | head -n 1 > {}", input, output)), (String::new(), 0));
+        assert_eq!(eval(&mut shell, &format!("echo c | cat >> {}", output)), (String::new(), 0));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "a\nc\n");
+        assert_eq!(eval(&mut shell, &format!("cat < {} | sort | tr a-z A-Z", input)), ("A\nB\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo hi | cat | cat"), ("hi\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, &format!("cat {} | wc -l > /dev/null", input)), (String::new(), 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_go_where_a_stages_stderr_is_redirected() {
+        let _lock = lock();
+        let dir = scratch();
+        let errors_file = dir.join("errors").display().to_string();
+        let mut shell = shell();
+        let captured = capture_errors(&mut shell);
+        assert_eq!(eval(&mut shell, "cd /nonexistent_zz 2>/dev/null"), (String::new(), 1));
+        assert_eq!(eval(&mut shell, "sh -c 'echo gone >&2' 2>/dev/null | cat"), (String::new(), 0));
+        assert_eq!(errors(&captured), "");
+        let line = format!("sh -c 'echo oops >&2; echo out' 2> {0}; nonexist_zz 2>> {0}", errors_file);
+        assert_eq!(eval(&mut shell, &line), ("out\n".to_string(), 127));
+        assert_eq!(fs::read_to_string(&errors_file).unwrap(), "oops\nnonexist_zz: command not found\n");
+        // the redirect lasts only as long as its command
+        eval(&mut shell, "cd /nonexistent_zz");
+        assert!(errors(&captured).contains("No such file"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn a_redirected_program_writes_straight_to_the_file() {
+        use std::time::{Duration, Instant};
+        let _lock = lock();
+        let dir = scratch();
+        let output = dir.join("out");
+        let watched = output.clone();
+        // sees the first line arrive while the program is still running
+        let watcher = thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if fs::read(&watched).is_ok_and(|read| read == b"a\n") {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        });
+        let mut shell = shell();
+        let line = format!("sh -c 'echo a; sleep 1; echo b' > {}", output.display());
+        assert_eq!(eval(&mut shell, &line), (String::new(), 0));
+        assert!(watcher.join().unwrap(), "the output was held back until the program exited");
+        assert_eq!(fs::read_to_string(&output).unwrap(), "a\nb\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exported_variables_reach_programs_until_unset() {
+        let _lock = lock();
+        let _restore = Restore::new(&["CHITIN_X"]);
+        let mut shell = shell();
+        let line = r#"export CHITIN_X="it's  \"odd\" $$"; sh -c 'echo "$CHITIN_X"'"#;
+        let (shown, status) = eval(&mut shell, line);
+        let value = env::var("CHITIN_X").unwrap();
+        assert!(value.starts_with(r#"it's  "odd" "#), "{}", value);
+        assert_eq!((shown, status), (format!("{}\n", value), 0));
+        // the listing runs again to give back exactly the same value
+        let (listing, _) = eval(&mut shell, "export");
+        let exported = listing.lines().find(|line| line.starts_with("export CHITIN_X=")).unwrap().to_string();
+        assert_eq!(eval(&mut shell, "unset CHITIN_X; sh -c 'echo ${CHITIN_X-gone}'"), ("gone\n".to_string(), 0));
+        assert_eq!(env::var_os("CHITIN_X"), None);
+        eval(&mut shell, &exported);
+        assert_eq!(env::var("CHITIN_X").unwrap(), value);
+    }
+
+    #[test]
+    fn builtins_end_their_output_in_exactly_one_newline() {
+        let _lock = lock();
+        let _restore = Restore::new(&[]);
+        let dir = scratch();
+        let mut shell = shell();
+        let run = |shell: &mut Shell, line: &str| {
+            let mut out = Vec::new();
+            shell.eval(Expr::try_from(Lexer::new(line)).unwrap(), &mut out).unwrap();
+            out
+        };
+        run(&mut shell, &format!("cd {}", dir.display()));
+        assert_eq!(run(&mut shell, "pwd"), format!("{}\n", dir.display()).into_bytes());
+        assert_eq!(run(&mut shell, "echo a  b"), b"a b\n");
+        assert_eq!(run(&mut shell, "echo"), b"\n");
+        assert_eq!(run(&mut shell, "echo -n a b"), b"a b");
+        assert_eq!(run(&mut shell, "echo -n"), b"");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn statuses_wrap_into_a_byte() {
+        assert_eq!([0, 255, 256, 257, -1, -256, 1000].map(wrap_status), [0, 255, 0, 1, 255, 0, 232]);
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "exit 256").1, 0);
+        assert_eq!(eval(&mut shell, "exit 257").1, 1);
+        assert_eq!(eval(&mut shell, "exit -1").1, 255);
+        assert_eq!(eval(&mut shell, "sh -c 'exit 300'; echo $?"), ("44\n".to_string(), 0));
+        #[cfg(unix)]
+        assert_eq!(eval(&mut shell, "sh -c 'kill -9 $$'; echo $?"), ("137\n".to_string(), 0));
+    }
+
+    #[test]
+    fn aliases_can_be_defined_used_listed_and_removed() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "alias say='echo said' two='say it'"), (String::new(), 0));
+        assert_eq!(eval(&mut shell, "say hi; \\say hi"), ("said hi\n".to_string(), 127));
+        assert_eq!(eval(&mut shell, "two again"), ("said it again\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "alias"), ("alias say='echo said'\nalias two='say it'\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "alias two"), ("alias two='say it'\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "alias none").1, 1);
+        assert_eq!(eval(&mut shell, "unalias say; two"), (String::new(), 127));
+        assert_eq!(eval(&mut shell, "unalias say").1, 1);
+        assert_eq!(eval(&mut shell, "unalias -a; alias"), (String::new(), 0));
+    }
+
+    #[test]
+    fn aliases_never_expand_themselves() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "alias echo='echo x'; echo y"), ("x y\n".to_string(), 0));
+        // each refers to the other, so expansion stops at a missing command
+        assert_eq!(eval(&mut shell, "alias ping_zz='pong_zz 1' pong_zz='ping_zz 2'; ping_zz").1, 127);
+    }
+
+    #[test]
+    fn exiting_in_a_pipeline_ends_only_the_pipeline() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "echo a | exit 4"), (String::new(), 4));
+        assert_eq!(eval(&mut shell, "echo a | exit 4; echo after"), ("after\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "exit 3 | echo b; echo after"), ("b\nafter\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "false; echo a | exit; echo $?"), ("1\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo a | exit 5 || echo failed"), ("failed\n".to_string(), 0));
+    }
+
+    #[test]
+    fn exit_leaves_with_the_status_given() {
+        let _lock = lock();
+        let mut shell = shell();
+        let mut exit = |line| match shell.eval(Expr::try_from(Lexer::new(line)).unwrap(), &mut Vec::new()) {
+            Ok(ResultKind::Exit(code)) => Some(code),
+            _ => None,
+        };
+        assert_eq!(exit("false; exit"), Some(None));
+        assert_eq!(exit("exit 0"), Some(Some(0)));
+        assert_eq!(exit("exit 42"), Some(Some(42)));
+        assert_eq!(exit("exit abc"), Some(Some(2)));
+        assert_eq!(exit("exit 1 2"), None);
+        assert_eq!(shell.status, 1);
+    }
+
+    /// Input whose first read is interrupted by a signal.
+    struct Interrupting<'a>(bool, &'a [u8]);
+
+    impl io::Read for Interrupting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1.read(buf)
+        }
+    }
+
+    impl BufRead for Interrupting<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if std::mem::take(&mut self.0) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            Ok(self.1)
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.1 = &self.1[amount..];
+        }
+    }
+
+    #[test]
+    fn reading_a_command_joins_continued_lines() {
+        let mut buffer = String::new();
+        let mut input = &b"echo a \\\nb\nnext\n"[..];
+        assert_eq!(read_command(&mut input, &mut Vec::new(), &mut buffer).unwrap(), 9);
+        assert_eq!(buffer, "echo a b\n");
+        buffer.clear();
+        assert_eq!(read_command(&mut input, &mut Vec::new(), &mut buffer).unwrap(), 5);
+        assert_eq!(read_command(&mut &b"last"[..], &mut Vec::new(), &mut buffer).unwrap(), 4);
+        assert_eq!(buffer, "next\nlast");
+        assert_eq!(read_command(&mut input, &mut Vec::new(), &mut buffer).unwrap(), 0);
+        assert_eq!(read_line(&mut &b"\xff\n"[..], &mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn only_ctrl_c_cuts_a_line_short() {
+        let _lock = lock();
+        let mut buffer = String::new();
+        assert_eq!(read_line(&mut Interrupting(true, b"a\n"), &mut buffer).unwrap(), 2);
+        signal::catch_interrupts(false);
+        // SAFETY: the handler just installed only sets a flag
+        unsafe { libc::raise(libc::SIGINT) };
+        let cut = read_line(&mut Interrupting(true, b"b\n"), &mut buffer);
+        signal::clear_interrupt();
+        assert_eq!(cut.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(buffer, "a\n");
+    }
+
+    #[test]
+    fn an_exit_ends_a_batch_and_is_passed_up() {
+        let _lock = lock();
+        let mut shell = shell();
+        let exited = batch(&b"true\nexit 3\nfalse\n"[..], &mut Vec::new(), "rc", false, &mut shell);
+        assert!(matches!(exited, Ok(ResultKind::Exit(Some(3)))));
+        assert_eq!(shell.status, 3);
+        let finished = batch(&b"true\nfalse\n"[..], &mut Vec::new(), "rc", false, &mut shell);
+        assert!(matches!(finished, Ok(ResultKind::Status(1))));
+    }
+
+    #[test]
+    fn a_very_long_pipeline_runs_without_recursing() {
+        let _lock = lock();
+        let mut shell = shell();
+        let line = format!("{}echo done", "echo a | ".repeat(20_000));
+        assert_eq!(eval(&mut shell, &line), ("done\n".to_string(), 0));
+    }
+
+    #[test]
+    fn a_command_that_cannot_run_does_not_stop_the_line() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "nonexist_zz; echo after"), ("after\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "cd /nonexistent; echo $?"), ("1\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo a; nonexist_zz"), ("a\n".to_string(), 127));
+    }
+
+    #[test]
+    fn a_failing_substitution_still_gives_its_output() {
+        let _lock = lock();
+        let mut shell = shell();
+        assert_eq!(eval(&mut shell, "echo \"a$(nonexist_zz)b\""), ("ab\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "echo $(echo out; nonexist_zz)"), ("out\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "x=$(nonexist_zz)"), (String::new(), 127));
+        assert_eq!(eval(&mut shell, "x=$(false) y=$(true)"), (String::new(), 0));
+        assert_eq!(eval(&mut shell, "x=$(exit 3); echo $?"), ("3\n".to_string(), 0));
+        assert_eq!(eval(&mut shell, "x=1"), (String::new(), 0));
+    }
+
+    #[test]
+    fn script_errors_name_the_line_they_happened_on() {
+        let _lock = lock();
+        let mut shell = shell();
+        let captured = capture_errors(&mut shell);
+        batch(&b"true\nnonexist_zz\necho >\n"[..], &mut Vec::new(), "rc", false, &mut shell).unwrap();
+        assert_eq!(errors(&captured), "rc: line 2: nonexist_zz: command not found\nrc: line 3: expected argument\n");
+        // a continued command is named by the line it starts on
+        batch(&b"true\nnonexist_zz \\\n  arg\n"[..], &mut Vec::new(), "rc", false, &mut shell).unwrap();
+        assert_eq!(errors(&captured), "rc: line 2: nonexist_zz: command not found\n");
+        assert_eq!(eval(&mut shell, "nonexist_zz").1, 127);
+        assert_eq!(errors(&captured), "nonexist_zz: command not found\n");
+    }
+
+    #[test]
+    fn piped_commands_print_only_their_output() {
+        let _lock = lock();
+        let mut shell = shell();
+        let mut out = Vec::new();
+        batch(&b"echo a\necho b\n"[..], &mut out, "chitin", false, &mut shell).unwrap();
+        assert_eq!(out, b"a\nb\n");
+        out.clear();
+        batch(&b"sh -c 'echo c'\n\necho d\n"[..], &mut out, "chitin", false, &mut shell).unwrap();
+        assert_eq!(out, b"c\nd\n");
+    }
+
+    /// Input typed at a terminal a piece at a time, where an empty piece is
+    /// a Ctrl-D that reads as the end of input once.
+    struct Typed(std::collections::VecDeque<&'static [u8]>);
+
+    impl io::Read for Typed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.fill_buf()?.read(buf)?;
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for Typed {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            match self.0.front() {
+                Some(&[]) => {
+                    self.0.pop_front();
+                    Ok(&[])
+                }
+                Some(&piece) => Ok(piece),
+                None => Ok(&[]),
+            }
+        }
+
+        fn consume(&mut self, amount: usize) {
+            if let Some(piece) = self.0.front_mut().filter(|_| amount > 0) {
+                *piece = &piece[amount..];
+                if piece.is_empty() {
+                    self.0.pop_front();
+                }
+            }
+        }
+    }
+
+    /// What `interactive` prints when `pieces` are typed at it.
+    fn type_at_prompt(shell: &mut Shell, pieces: &[&'static [u8]]) -> String {
+        colored::control::set_override(false);
+        let mut out = Vec::new();
+        interactive(shell, &mut Typed(pieces.iter().copied().collect()), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ignoreeof_sets_how_many_eofs_are_ignored() {
+        let _lock = lock();
+        let _restore = Restore::new(&["IGNOREEOF", "CHITIN_PS1"]);
+        env::remove_var("CHITIN_PS1");
+        let mut shell = shell();
+        let reminders = |out: &str| out.matches("Use \"exit\" to leave the shell.").count();
+        env::set_var("IGNOREEOF", "2");
+        let out = type_at_prompt(&mut shell, &[b"", b"", b"", b"echo unread\n"]);
+        assert_eq!((reminders(&out), out.contains("unread")), (2, false));
+        // any input starts the count again
+        let out = type_at_prompt(&mut shell, &[b"", b"", b"echo hi\n", b"", b"", b""]);
+        assert_eq!((reminders(&out), out.contains("hi\n")), (4, true));
+        env::remove_var("IGNOREEOF");
+        let out = type_at_prompt(&mut shell, &[b"", b"echo unread\n"]);
+        assert_eq!(out, "chitin> \n");
+    }
+
+    #[test]
+    fn a_closed_input_ends_the_prompt_loop() {
+        let _lock = lock();
+        let _restore = Restore::new(&["IGNOREEOF", "CHITIN_PS1"]);
+        env::remove_var("CHITIN_PS1");
+        env::remove_var("IGNOREEOF");
+        colored::control::set_override(false);
+        let mut shell = shell();
+        shell.status = 3;
+        let mut out = Vec::new();
+        assert_eq!(interactive(&mut shell, &mut &b"echo last\n"[..], &mut out).unwrap(), 0);
+        assert_eq!(out, b"chitin> last\nchitin> \n");
+        // a reader that stays closed runs out however many EOFs are ignored
+        env::set_var("IGNOREEOF", "3");
+        assert_eq!(interactive(&mut shell, &mut &b""[..], &mut Vec::new()).unwrap(), 0);
+    }
+
+    #[test]
+    fn a_line_runs_the_commands_before_its_syntax_error() {
+        let _lock = lock();
+        let _restore = Restore::new(&["IGNOREEOF", "CHITIN_PS1"]);
+        env::remove_var("CHITIN_PS1");
+        env::remove_var("IGNOREEOF");
+        let mut shell = shell();
+        let captured = capture_errors(&mut shell);
+        let mut out = Vec::new();
+        let status = interactive(&mut shell, &mut &b"echo ran; echo hi >\n"[..], &mut out).unwrap();
+        assert_eq!((String::from_utf8(out).unwrap(), status), ("chitin> ran\nchitin> \n".to_string(), SYNTAX_ERROR));
+        assert_eq!(errors(&captured), "expected argument\n");
     }
 }
@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The status of a command line cut short by Ctrl-C: 128 plus SIGINT.
+pub const INTERRUPT_STATUS: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C set a flag instead of killing the shell. Foreground programs
+/// share the shell's process group, so the terminal still interrupts them,
+/// and exec resets the handler to the default in every child. With
+/// `cut_reads` set, Ctrl-C also makes a read that is waiting for input fail
+/// with `ErrorKind::Interrupted` instead of carrying on.
+#[cfg(unix)]
+pub fn catch_interrupts(cut_reads: bool) {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe, and the sigaction struct is fully initialized
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = if cut_reads { 0 } else { libc::SA_RESTART };
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_interrupts(_cut_reads: bool) {}
+
+/// Whether Ctrl-C was pressed since the flag was last cleared.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
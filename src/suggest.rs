@@ -0,0 +1,110 @@
+use std::{collections::BTreeSet, env, fs};
+
+use crate::ast::BUILTINS;
+
+/// Edit distance between two strings, counted in chars, where swapping two
+/// adjacent chars is a single edit like insertions, deletions and
+/// substitutions (optimal string alignment distance).
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // rows[i][j] is the distance between the first i chars of a and first j of b
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i];
+        for j in 1..=b.len() {
+            let prev = &rows[i - 1];
+            let mut distance = (prev[j - 1] + usize::from(a[i - 1] != b[j - 1]))
+                .min(prev[j] + 1)
+                .min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            row.push(distance);
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The candidate nearest to `typo`, if any is close enough to be a likely
+/// typo rather than a different command. Ties go to the first candidate.
+pub fn closest<'a>(typo: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let threshold = if typo.chars().count() < 4 { 1 } else { 2 };
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(typo, candidate), candidate))
+        .filter(|&(distance, _)| distance > 0 && distance <= threshold)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Every name that would run as a command: builtins and the executables
+/// found on `PATH`, sorted and deduplicated.
+pub fn commands() -> BTreeSet<String> {
+    let mut names: BTreeSet<String> = BUILTINS.iter().map(|name| name.to_string()).collect();
+    let path = env::var_os("PATH").unwrap_or_default();
+    for dir in env::split_paths(&path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if is_executable(&entry) {
+                names.extend(entry.file_name().into_string());
+            }
+        }
+    }
+    names
+}
+
+#[cfg(unix)]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    entry.metadata().is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    entry.metadata().is_ok_and(|meta| meta.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_are_counted_in_chars() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("cd", ""), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("é", "e"), 1);
+    }
+
+    #[test]
+    fn swapping_adjacent_chars_is_one_edit() {
+        assert_eq!(edit_distance("sl", "ls"), 1);
+        assert_eq!(edit_distance("gti", "git"), 1);
+        assert_eq!(edit_distance("abc", "ca"), 3);
+    }
+
+    #[test]
+    fn short_names_allow_one_edit_and_longer_ones_two() {
+        assert_eq!(closest("gti", ["git"]), Some("git"));
+        assert_eq!(closest("tg", ["git"]), None);
+        assert_eq!(closest("ecoh", ["echo"]), Some("echo"));
+        assert_eq!(closest("ehco_", ["echo"]), Some("echo"));
+        assert_eq!(closest("xxxx", ["echo"]), None);
+    }
+
+    #[test]
+    fn an_exact_match_is_not_a_suggestion() {
+        assert_eq!(closest("ls", ["ls"]), None);
+    }
+
+    #[test]
+    fn the_nearest_candidate_wins_and_ties_go_to_the_first() {
+        assert_eq!(closest("mkdri", ["mkdirs", "mkdir"]), Some("mkdir"));
+        assert_eq!(closest("cd", ["c", "d"]), Some("c"));
+        assert_eq!(closest("cd", ["d", "c"]), Some("d"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{collections::hash_map::RandomState, fs::{self, File, OpenOptions}, hash::{BuildHasher, Hasher}, io, path::{Path, PathBuf}};
+
+const SUFFIX_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const MIN_PLACEHOLDERS: usize = 3;
+const ATTEMPTS: u32 = 100;
+
+/// Creates a new file named after `template`, whose trailing `X`s are
+/// replaced with random characters. Creation uses `O_EXCL`, so an existing
+/// file is never reused. Only the template's file name has to be UTF-8.
+pub fn file(template: &Path) -> io::Result<(PathBuf, File)> {
+    unique(template, |path| {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)
+    })
+}
+
+/// Creates a new directory named after `template`, like [`file`].
+pub fn dir(template: &Path) -> io::Result<PathBuf> {
+    unique(template, |path| {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(path)
+    })
+    .map(|(path, ())| path)
+}
+
+fn unique<T>(template: &Path, create: impl Fn(&PathBuf) -> io::Result<T>) -> io::Result<(PathBuf, T)> {
+    let name = template.file_name().unwrap_or_default().to_str().ok_or_else(
+        || io::Error::new(io::ErrorKind::InvalidInput, "template name is not valid UTF-8")
+    )?;
+    let stem = name.trim_end_matches('X');
+    let placeholders = name.len() - stem.len();
+    if placeholders < MIN_PLACEHOLDERS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too few X's in template"));
+    }
+    for _ in 0..ATTEMPTS {
+        let path = template.with_file_name(format!("{}{}", stem, random_suffix(placeholders)));
+        match create(&path) {
+            Ok(created) => return Ok((path, created)),
+            Err(reason) if reason.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(reason) => return Err(reason),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "could not find an unused name for template"))
+}
+
+fn random_suffix(len: usize) -> String {
+    // every RandomState is keyed differently, which is random enough for names
+    (0..len)
+        .map(|_| {
+            let index = RandomState::new().build_hasher().finish() as usize % SUFFIX_CHARS.len();
+            SUFFIX_CHARS[index] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn only_the_trailing_xs_are_replaced() {
+        let parent = dir(&env::temp_dir().join("chitin-temp.XXXXXX")).unwrap();
+        let (path, _) = file(&parent.join("aXbXXXX")).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("aXb") && name.len() == 7, "{}", name);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+            assert_eq!(fs::metadata(&parent).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn a_template_needs_three_xs() {
+        let template = env::temp_dir().join("chitin-temp.XX");
+        assert_eq!(file(&template).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(dir(&template).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}